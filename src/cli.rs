@@ -0,0 +1,366 @@
+use crate::cluster::strip_port;
+use crate::config::{
+  BalancingStrategy, HeaderOps, LbrpConfig, LbrpMode, LbrpPCMode, LbrpYBOBMode, Service, load_config, unix_socket_path,
+};
+use crate::request_rules::RulesHandler;
+use crate::router::{HostPattern, PathMatcher, describe_router, fill_placeholders};
+
+pub(crate) const USAGE: &str = "Usage: lbrp [COMMAND]
+
+Commands:
+  (none)               boot the server
+  check                validate `lbrp-service.yaml` and `lbrp-config.json` without binding ports
+  explain <host> <path>
+                       print which service, middlewares, rules and upstreams would handle a request
+  dump-router          print the router tree built from `lbrp-config.json`
+  help                 print this message";
+
+/// Command given on the command line.
+pub(crate) enum Command {
+  Serve,
+  Check,
+  Explain { host: String, path: String },
+  DumpRouter,
+}
+
+impl Command {
+  /// Parses arguments after the binary name; prints usage and exits on invalid arguments.
+  pub(crate) fn parse(mut args: impl Iterator<Item = String>) -> Self {
+    let command = match args.next().as_deref() {
+      None => Some(Self::Serve),
+      Some("check") => Some(Self::Check),
+      Some("explain") => match (args.next(), args.next()) {
+        (Some(host), Some(path)) => Some(Self::Explain { host, path }),
+        _ => None,
+      },
+      Some("dump-router") => Some(Self::DumpRouter),
+      Some("help" | "--help" | "-h") => {
+        println!("{USAGE}");
+        std::process::exit(0);
+      }
+      Some(_) => None,
+    };
+    match command {
+      Some(command) if args.next().is_none() => command,
+      _ => {
+        eprintln!("{USAGE}");
+        std::process::exit(2);
+      }
+    }
+  }
+}
+
+/// Runs a command which doesn't boot the server; exits with `1` on failure.
+pub(crate) async fn run(command: Command, config_file: &str) {
+  let config = match load_config(config_file) {
+    Ok(config) => config,
+    Err(e) => {
+      println!("`{config_file}` is invalid: {e}");
+      std::process::exit(1);
+    }
+  };
+
+  match command {
+    Command::Serve => {}
+    Command::Check => println!("`{config_file}` is OK"),
+    Command::Explain { host, path } => println!("{}", explain(&config, &host, &path)),
+    Command::DumpRouter => println!("{:?}", describe_router(&config).await),
+  }
+}
+
+fn strategy_name(strategy: &BalancingStrategy) -> String {
+  match strategy {
+    BalancingStrategy::RoundRobin => "round robin".to_string(),
+    BalancingStrategy::Weighted => "weighted".to_string(),
+    BalancingStrategy::LeastConnections => "least connections".to_string(),
+    BalancingStrategy::Random => "random".to_string(),
+    BalancingStrategy::ConsistentHash { by } => format!("consistent hash by {by:?}"),
+  }
+}
+
+fn header_ops(ops: &HeaderOps) -> String {
+  let mut described = vec![];
+  if let Some(remove) = &ops.remove {
+    described.push(format!("remove {remove:?}"));
+  }
+  if let Some(set) = &ops.set {
+    described.push(format!("set {:?}", set.keys().collect::<Vec<_>>()));
+  }
+  if let Some(append) = &ops.append {
+    described.push(format!("append {:?}", append.keys().collect::<Vec<_>>()));
+  }
+  described.join(", ")
+}
+
+/// Describes how the request would be handled with the config.
+fn explain(config: &LbrpConfig, host: &str, path: &str) -> String {
+  let host = strip_port(host);
+  let path = if path.starts_with('/') {
+    path.to_string()
+  } else {
+    format!("/{path}")
+  };
+  let mut lines = vec![format!("Request to `{host}{path}`:")];
+
+  match &config.lbrp_mode {
+    LbrpMode::Supervisor => {
+      lines.push("  the node is a supervisor and doesn't serve requests".to_string());
+      return lines.join("\n");
+    }
+    LbrpMode::PC(LbrpPCMode::Parent) => {
+      lines.push("  proxied to a child node serving the host, `503` if there is none".to_string());
+      return lines.join("\n");
+    }
+    LbrpMode::Ybob(LbrpYBOBMode::OlderBrother) => {
+      lines.push("  proxied to a younger brother serving the host; if there is none, handled as follows".to_string());
+    }
+    _ => {}
+  }
+
+  let err_handler = config.services.iter().find_map(|s| match s {
+    Service::ErrorHandler(err_handler) => Some(err_handler),
+    _ => None,
+  });
+  if let Some(err_handler) = err_handler
+    && ([
+      "/400", "/401", "/403", "/404", "/405", "/413", "/423", "/500", "/502", "/503", "/504", "/oops",
+    ]
+    .contains(&path.as_str())
+      || err_handler.static_files.iter().any(|f| path == format!("/{f}")))
+  {
+    lines.push(format!(
+      "  served by `error_handler` from `{}` for any host",
+      err_handler.dist_dir.display()
+    ));
+    return lines.join("\n");
+  }
+
+  let mut services = config
+    .common_services()
+    .filter_map(|s| {
+      let pattern = HostPattern::parse(&s.from).ok()?;
+      let captures = pattern.captures(host)?;
+      Some((pattern.precedence(), PathMatcher::new(s), captures, s))
+    })
+    .collect::<Vec<_>>();
+  if services.is_empty() {
+    lines.push("  no service serves the host, `404`".to_string());
+    return lines.join("\n");
+  }
+  services.sort_by_key(|(precedence, matcher, _, _)| (*precedence, matcher.precedence()));
+  let Some((_, matcher, captures, service)) = services.into_iter().find(|(_, matcher, _, _)| matcher.matches(&path))
+  else {
+    lines.push("  no service of the host serves the path, `404`".to_string());
+    return lines.join("\n");
+  };
+  lines.push(format!(
+    "  service: `{}` (from `{}{}`)",
+    service.service_name,
+    service.from,
+    service.path_prefix()
+  ));
+  if let Some(regex) = &service.path_regex {
+    lines.push(format!("  path matches `{regex}`"));
+  }
+
+  let mut middlewares = vec![];
+  for (kind, ops) in [
+    ("request", &service.request_headers),
+    ("response", &service.response_headers),
+  ] {
+    if let Some(ops) = ops {
+      middlewares.push(format!("{kind} headers: {}", header_ops(ops)));
+    }
+  }
+  if service.forwarded_headers.is_some_and(|v| v) {
+    middlewares.push(format!(
+      "`Forwarded`, `X-Forwarded-*` and `X-Real-IP` headers, kept from {} trusted proxies",
+      config.trusted_proxies.as_ref().map(|p| p.len()).unwrap_or_default()
+    ));
+  }
+  if let Some(version) = &service.send_proxy_protocol {
+    middlewares.push(format!("PROXY protocol {version:?} header is sent to upstreams"));
+  }
+  if let Some(header_name) = &service.provide_ip_as_header {
+    middlewares.push(format!("client IP is provided in `{header_name}` header"));
+  }
+  #[cfg(feature = "authnz")]
+  if let Some(tags) = &service.require_subdomain_auth {
+    middlewares.push(format!("authnz: {} access tag(s) required", tags.len()));
+  }
+  if let Some(r#static) = config.services.iter().find_map(|s| match s {
+    Service::CommonStatic(r#static) => Some(r#static),
+    _ => None,
+  }) {
+    middlewares.push(format!(
+      "static files from `{}` for routes {:?}",
+      r#static.path.display(),
+      r#static.static_routes
+    ));
+  }
+  if err_handler.is_some() && !service.skip_err_handling.is_some_and(|v| v) {
+    middlewares.push("error pages of `error_handler` for error responses".to_string());
+  }
+  if let Some(origins) = &service.cors_domains {
+    middlewares.push(format!("CORS for {origins:?}"));
+  }
+  if middlewares.is_empty() {
+    lines.push("  middlewares: none".to_string());
+  } else {
+    lines.push("  middlewares:".to_string());
+    for (idx, middleware) in middlewares.iter().enumerate() {
+      lines.push(format!("    {}. {middleware}", idx + 1));
+    }
+  }
+
+  let mut path = path;
+  if let Some(rules) = service.rules.as_ref().filter(|rules| !rules.is_empty()) {
+    let (described, rewritten) = RulesHandler::new(rules).explain(&path);
+    if described.is_empty() {
+      lines.push("  rules: none applies".to_string());
+    } else {
+      lines.push("  rules, applied before static files and proxying:".to_string());
+      for rule in described {
+        lines.push(format!("    - {rule}"));
+      }
+    }
+    let Some(rewritten) = rewritten else {
+      lines.push("  the request is finished by the rule".to_string());
+      return lines.join("\n");
+    };
+    path = rewritten;
+  }
+
+  lines.push(format!(
+    "  upstreams ({}):",
+    strategy_name(&service.balancing.clone().unwrap_or_default())
+  ));
+  let upstream_path = matcher.upstream_path(&path);
+  for upstream in service.to.iter() {
+    if let Some(socket) = unix_socket_path(upstream.url(), service.working_dir.as_deref()) {
+      lines.push(format!(
+        "    - {upstream_path} over unix socket `{}` (weight {})",
+        socket.display(),
+        upstream.weight()
+      ));
+      continue;
+    }
+    lines.push(format!(
+      "    - {}{upstream_path} (weight {})",
+      fill_placeholders(upstream.url(), &captures).trim_end_matches('/'),
+      upstream.weight()
+    ));
+  }
+  let timeouts = [
+    ("connect", service.connect_timeout),
+    ("request", service.request_timeout),
+    ("idle", service.idle_timeout),
+  ]
+  .iter()
+  .filter_map(|(name, secs)| secs.map(|secs| format!("{name} {secs}s")))
+  .collect::<Vec<_>>();
+  if !timeouts.is_empty() {
+    lines.push(format!("  timeouts: {}", timeouts.join(", ")));
+  }
+  if let Some(pool) = &service.connection_pool {
+    lines.push(format!(
+      "  connections: {} idle per host, kept {}s{}",
+      pool
+        .max_idle_per_host
+        .map(|max| max.to_string())
+        .unwrap_or("unlimited".to_string()),
+      pool.idle_keep_alive.unwrap_or(90),
+      if pool.http2_prior_knowledge.is_some_and(|v| v) {
+        ", HTTP/2 prior knowledge"
+      } else {
+        ""
+      }
+    ));
+  }
+  if let Some(retry) = &service.retry {
+    lines.push(format!(
+      "  retries: up to {}{}{}",
+      retry.attempts(),
+      if retry.idempotent_only.unwrap_or(true) {
+        " for idempotent requests"
+      } else {
+        ""
+      },
+      if retry.next_upstream() {
+        ", on the next upstream"
+      } else {
+        ""
+      }
+    ));
+  }
+
+  lines.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+
+  fn config(services: serde_json::Value) -> LbrpConfig {
+    LbrpConfig {
+      services: serde_json::from_value(services).unwrap(),
+      ..Default::default()
+    }
+  }
+
+  #[test]
+  fn explains_matched_service_and_upstream_path() {
+    let config = config(json!([
+      { "type": "common_service", "service_name": "site", "from": "example.com", "to": "http://127.0.0.1:8020" },
+      {
+        "type": "common_service",
+        "service_name": "api",
+        "from": "example.com",
+        "to": "http://127.0.0.1:8021",
+        "path_prefix": "/api",
+        "strip_path_prefix": true
+      },
+    ]));
+    let explained = explain(&config, "example.com:443", "/api/users");
+    assert!(
+      explained.contains("service: `api` (from `example.com/api`)"),
+      "{explained}"
+    );
+    assert!(
+      explained.contains("- http://127.0.0.1:8021/users (weight 1)"),
+      "{explained}"
+    );
+
+    let explained = explain(&config, "example.com", "/about");
+    assert!(explained.contains("service: `site`"), "{explained}");
+    assert!(explain(&config, "other.com", "/").contains("no service serves the host, `404`"));
+  }
+
+  #[test]
+  fn explains_effective_rules() {
+    let config = config(json!([{
+      "type": "common_service",
+      "service_name": "app",
+      "from": "example.com",
+      "to": "http://127.0.0.1:8020",
+      "rules": [
+        { "match": { "path": "^/old/(.*)$" }, "action": { "type": "rewrite", "path": "/new/$1" } },
+        { "match": { "path": "^/new/", "methods": ["POST"] }, "action": { "type": "respond", "status": 405 } },
+        { "match": { "path": "^/legacy$" }, "action": { "type": "redirect", "location": "/", "status": 301 } },
+      ]
+    }]));
+    let explained = explain(&config, "example.com", "/old/page");
+    assert!(
+      explained.contains("rule #1 changes the path to `/new/page`"),
+      "{explained}"
+    );
+    assert!(explained.contains("rule #2 depends on the method"), "{explained}");
+    assert!(explained.contains("- http://127.0.0.1:8020/new/page"), "{explained}");
+
+    let explained = explain(&config, "example.com", "/legacy");
+    assert!(explained.contains("rule #3 redirects to `/` with `301`"), "{explained}");
+    assert!(explained.contains("the request is finished by the rule"), "{explained}");
+    assert!(!explained.contains("upstreams"), "{explained}");
+  }
+}
@@ -0,0 +1,235 @@
+use impulse_server_kit::prelude::*;
+use impulse_server_kit::salvo::conn::{Listener, TcpListener};
+use impulse_server_kit::salvo::{Handler, Server};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+use crate::config::ClusterConfig;
+use crate::error_handling::{GATEWAY_ERROR_KEY, GatewayError};
+use crate::proxy_client::ModifiedReqwestClient;
+
+pub(crate) mod pc;
+pub(crate) mod registry;
+pub(crate) mod supervisor;
+pub(crate) mod ybob;
+
+use registry::NodeRegistry;
+
+/// Prefix of cluster control endpoints.
+pub(crate) const CLUSTER_API: &str = "/--lbrp-cluster";
+/// Header with the shared cluster secret.
+pub(crate) const CLUSTER_SECRET_HEADER: &str = "LBRP-Cluster-Secret";
+
+/// Node heartbeat; the first one registers the node.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub(crate) struct NodeAnnouncement {
+  pub(crate) node_id: String,
+  /// URL to proxy requests to this node.
+  pub(crate) public_url: String,
+  /// Hosts served by this node; empty list means any host.
+  pub(crate) hosts: Vec<String>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub(crate) struct NodeStatus {
+  pub(crate) node_id: String,
+  pub(crate) public_url: String,
+  pub(crate) hosts: Vec<String>,
+  pub(crate) last_seen_secs_ago: u64,
+}
+
+/// Background tasks and control routes of cluster modes, living until the next config reload.
+#[derive(Default)]
+pub(crate) struct ClusterRuntime {
+  background: Vec<tokio::task::JoinHandle<()>>,
+  control_routes: Vec<Router>,
+}
+
+impl ClusterRuntime {
+  pub(crate) fn spawn<F>(&mut self, task: F)
+  where
+    F: Future<Output = ()> + Send + 'static,
+  {
+    self.background.push(tokio::spawn(task));
+  }
+
+  /// Adds routes to be served under [`CLUSTER_API`] by the control server.
+  pub(crate) fn push_control(&mut self, router: Router) {
+    self.control_routes.push(router);
+  }
+
+  /// Starts the control server with collected routes if the node has `control_addr` specified.
+  pub(crate) fn start_control_server(&mut self, cluster: Option<&ClusterConfig>) {
+    let Some(cluster) = cluster else {
+      return;
+    };
+    let Some(control_addr) = cluster.control_addr.clone() else {
+      return;
+    };
+    if self.control_routes.is_empty() {
+      return;
+    }
+
+    let mut router = Router::with_path(CLUSTER_API).hoop(ClusterSecret::new(cluster.secret.clone()));
+    for route in self.control_routes.drain(..) {
+      router = router.push(route);
+    }
+    self.spawn(serve_control(control_addr, router));
+  }
+
+  /// Stops every background task, including the control server.
+  ///
+  /// Waits for aborted tasks, so the control address is free when this returns.
+  pub(crate) async fn stop(self) {
+    for handle in &self.background {
+      handle.abort();
+    }
+    for handle in self.background {
+      let _ = handle.await;
+    }
+  }
+}
+
+/// Rejects control and admin requests without the shared secret.
+pub(crate) struct ClusterSecret {
+  secret: Option<String>,
+}
+
+/// Compares secrets in time which doesn't depend on the position of the first difference.
+fn secrets_match(given: &[u8], expected: &[u8]) -> bool {
+  given.len() == expected.len() && given.iter().zip(expected).fold(0u8, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+impl ClusterSecret {
+  pub(crate) fn new(secret: Option<String>) -> Self {
+    Self { secret }
+  }
+}
+
+#[impulse_server_kit::salvo::async_trait]
+impl impulse_server_kit::salvo::Handler for ClusterSecret {
+  async fn handle(&self, req: &mut Request, depot: &mut Depot, res: &mut Response, ctrl: &mut salvo::FlowCtrl) {
+    if let Some(secret) = &self.secret
+      && req
+        .headers()
+        .get(CLUSTER_SECRET_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .is_none_or(|v| !secrets_match(v.as_bytes(), secret.as_bytes()))
+    {
+      tracing::warn!(remote_addr = req.remote_addr().to_string(), "Rejected cluster request with invalid secret");
+      res.status_code(StatusCode::UNAUTHORIZED);
+      ctrl.skip_rest();
+      return;
+    }
+    ctrl.call_next(req, depot, res).await;
+  }
+}
+
+/// Proxies every request to one of the registered nodes serving the requested host.
+///
+/// With `fallback` enabled, requests no node can serve go to the next handlers instead of `503`.
+pub(crate) struct NodeBalancer {
+  registry: Arc<NodeRegistry>,
+  client: reqwest::Client,
+  fallback: bool,
+}
+
+impl NodeBalancer {
+  pub(crate) fn new(registry: Arc<NodeRegistry>, fallback: bool) -> Self {
+    Self {
+      registry,
+      client: reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .unwrap(),
+      fallback,
+    }
+  }
+}
+
+#[impulse_server_kit::salvo::async_trait]
+impl impulse_server_kit::salvo::Handler for NodeBalancer {
+  #[tracing::instrument(
+    skip_all,
+    name = "node-balancer",
+    fields(
+      http.uri = req.uri().path(),
+      http.method = req.method().as_str()
+    )
+  )]
+  async fn handle(&self, req: &mut Request, depot: &mut Depot, res: &mut Response, ctrl: &mut salvo::FlowCtrl) {
+    let host = request_host(req);
+    let Some(node_url) = self.registry.elect(&host) else {
+      if self.fallback {
+        tracing::debug!(host, "No alive node serves the host, falling back to own services");
+        ctrl.call_next(req, depot, res).await;
+      } else {
+        tracing::warn!(host, "No alive node serves the host");
+        res.status_code(GatewayError::NoUpstream.status());
+        depot.insert(GATEWAY_ERROR_KEY, GatewayError::NoUpstream);
+        res.render(salvo::writing::Text::Plain("No alive node!"));
+      }
+      return;
+    };
+
+    // Host header is kept as is, so nodes can route by it.
+    let host_header = req
+      .headers()
+      .get(salvo::http::header::HOST)
+      .and_then(|v| v.to_str().ok())
+      .unwrap_or(host.as_str())
+      .to_owned();
+    // The whole path is forwarded regardless of the route this handler is mounted on.
+    ModifiedReqwestClient::new(self.client.clone(), &host_header)
+      .as_client(node_url)
+      .with_url_path_getter(|req, _depot| Some(req.uri().path().trim_start_matches('/').to_owned()))
+      .handle(req, depot, res, ctrl)
+      .await;
+    ctrl.skip_rest();
+  }
+}
+
+/// Returns the requested host without the port.
+pub(crate) fn request_host(req: &Request) -> String {
+  let host = req
+    .headers()
+    .get(salvo::http::header::HOST)
+    .and_then(|v| v.to_str().ok())
+    .or(req.uri().host())
+    .unwrap_or_default();
+  strip_port(host).to_owned()
+}
+
+pub(crate) fn strip_port(host: &str) -> &str {
+  if host.starts_with('[') {
+    host.split_once(']').map(|(h, _)| &h[1..]).unwrap_or(host)
+  } else {
+    host.split_once(':').map(|(h, _)| h).unwrap_or(host)
+  }
+}
+
+/// Serves the cluster control endpoints on a separate address.
+async fn serve_control(addr: String, router: Router) {
+  match TcpListener::new(addr.clone()).try_bind().await {
+    Ok(acceptor) => {
+      tracing::info!("Cluster control server is listening on `{}`", addr);
+      Server::new(acceptor).serve(router).await;
+    }
+    Err(e) => tracing::error!(error = ?e, "Can't bind cluster control server to `{}`!", addr),
+  }
+}
+
+/// Sends a request to the control endpoint of another node.
+pub(crate) fn control_request(
+  client: &reqwest::Client,
+  method: reqwest::Method,
+  base_url: &str,
+  endpoint: &str,
+  secret: Option<&str>,
+) -> reqwest::RequestBuilder {
+  let mut builder = client.request(method, format!("{}{CLUSTER_API}{endpoint}", base_url.trim_end_matches('/')));
+  if let Some(secret) = secret {
+    builder = builder.header(CLUSTER_SECRET_HEADER, secret);
+  }
+  builder
+}
@@ -0,0 +1,29 @@
+use impulse_server_kit::prelude::*;
+use std::sync::Arc;
+
+use crate::cluster::{ClusterRuntime, NodeBalancer};
+use crate::cluster::registry::{NodeRegistry, spawn_heartbeat, start_registry};
+use crate::config::LbrpConfig;
+use crate::error_handling::GatewayErrors;
+
+/// Starts accepting child registrations and returns the router proxying to child nodes.
+pub(crate) fn start_parent(config: &LbrpConfig, runtime: &mut ClusterRuntime) -> Router {
+  let cluster = config.cluster.clone().unwrap_or_default();
+  let registry = Arc::new(NodeRegistry::new(cluster.heartbeat_timeout()));
+  start_registry(&cluster, &registry, runtime);
+  parent_router(registry)
+}
+
+/// Router proxying to child nodes of the `registry`.
+pub(crate) fn parent_router(registry: Arc<NodeRegistry>) -> Router {
+  Router::with_path("{**rest_path}")
+    .hoop(GatewayErrors)
+    .goal(NodeBalancer::new(registry, false))
+}
+
+/// Spawns the child's heartbeat loop registering it at the parent node.
+pub(crate) fn spawn_child_heartbeat(config: &LbrpConfig, runtime: &mut ClusterRuntime) {
+  let cluster = config.cluster.clone().unwrap_or_default();
+  let parent = cluster.parent.clone().unwrap_or_default();
+  spawn_heartbeat(runtime, cluster, parent, config.hosts());
+}
@@ -0,0 +1,220 @@
+use impulse_server_kit::prelude::*;
+use impulse_server_kit::salvo::affix_state;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use crate::cluster::{ClusterRuntime, NodeAnnouncement, NodeStatus, control_request, strip_port};
+use crate::config::ClusterConfig;
+use crate::router::HostPattern;
+
+struct RegisteredNode {
+  public_url: String,
+  hosts: Vec<String>,
+  patterns: Vec<HostPattern>,
+  last_seen: Instant,
+}
+
+/// Nodes registered at this one with heartbeats.
+pub(crate) struct NodeRegistry {
+  nodes: RwLock<HashMap<String, RegisteredNode>>,
+  cursor: AtomicUsize,
+  timeout: Duration,
+}
+
+impl NodeRegistry {
+  pub(crate) fn new(timeout: Duration) -> Self {
+    Self {
+      nodes: RwLock::new(HashMap::new()),
+      cursor: AtomicUsize::new(0),
+      timeout,
+    }
+  }
+
+  /// Registers or refreshes the node; returns `true` for newly registered ones.
+  pub(crate) fn heartbeat(&self, announcement: NodeAnnouncement) -> bool {
+    let mut nodes = self.nodes.write().unwrap();
+    nodes
+      .insert(
+        announcement.node_id,
+        RegisteredNode {
+          public_url: announcement.public_url,
+          patterns: announcement
+            .hosts
+            .iter()
+            .filter_map(|h| HostPattern::parse(h).ok())
+            .collect(),
+          hosts: announcement.hosts.iter().map(|h| strip_port(h).to_owned()).collect(),
+          last_seen: Instant::now(),
+        },
+      )
+      .is_none()
+  }
+
+  /// Removes nodes without heartbeats for too long and returns their ids.
+  pub(crate) fn prune(&self) -> Vec<String> {
+    let mut nodes = self.nodes.write().unwrap();
+    let lost = nodes
+      .iter()
+      .filter(|(_, node)| node.last_seen.elapsed() > self.timeout)
+      .map(|(id, _)| id.clone())
+      .collect::<Vec<_>>();
+    for id in &lost {
+      nodes.remove(id);
+    }
+    lost
+  }
+
+  pub(crate) fn alive_count(&self) -> usize {
+    let nodes = self.nodes.read().unwrap();
+    nodes
+      .values()
+      .filter(|node| node.last_seen.elapsed() <= self.timeout)
+      .count()
+  }
+
+  /// Elects an alive node serving the host, round-robin among the most specific matches.
+  pub(crate) fn elect(&self, host: &str) -> Option<String> {
+    let nodes = self.nodes.read().unwrap();
+    let candidates = nodes
+      .iter()
+      .filter(|(_, node)| node.last_seen.elapsed() <= self.timeout)
+      .filter_map(|(id, node)| {
+        let precedence = if node.hosts.is_empty() {
+          Some(u8::MAX)
+        } else {
+          node
+            .patterns
+            .iter()
+            .filter(|p| p.matches(host))
+            .map(|p| p.precedence())
+            .min()
+        };
+        precedence.map(|precedence| (precedence, (id, node)))
+      })
+      .collect::<Vec<_>>();
+    let best = candidates.iter().map(|(precedence, _)| *precedence).min()?;
+    let mut candidates = candidates
+      .into_iter()
+      .filter(|(precedence, _)| *precedence == best)
+      .map(|(_, candidate)| candidate)
+      .collect::<Vec<_>>();
+    candidates.sort_unstable_by_key(|(id, _)| id.as_str());
+    let idx = self.cursor.fetch_add(1, Ordering::Relaxed) % candidates.len();
+    Some(candidates[idx].1.public_url.clone())
+  }
+
+  pub(crate) fn statuses(&self) -> Vec<NodeStatus> {
+    let nodes = self.nodes.read().unwrap();
+    nodes
+      .iter()
+      .map(|(id, node)| NodeStatus {
+        node_id: id.clone(),
+        public_url: node.public_url.clone(),
+        hosts: node.hosts.clone(),
+        last_seen_secs_ago: node.last_seen.elapsed().as_secs(),
+      })
+      .collect()
+  }
+}
+
+fn extract_registry(depot: &Depot) -> MResult<&Arc<NodeRegistry>> {
+  depot
+    .obtain::<Arc<NodeRegistry>>()
+    .map_err(|_| ServerError::from_private_str("Can't get node registry from depot!").with_500())
+}
+
+#[handler]
+#[tracing::instrument(skip_all, name = "node-heartbeat", level = "debug")]
+async fn node_heartbeat(depot: &mut Depot, req: &mut Request) -> MResult<Json<Vec<NodeStatus>>> {
+  let announcement = req.parse_json_simd::<NodeAnnouncement>().await.map_err(|e| {
+    ServerError::from_private(e)
+      .with_public("Invalid node announcement!")
+      .with_400()
+  })?;
+  let registry = extract_registry(depot)?;
+
+  let node_id = announcement.node_id.clone();
+  let public_url = announcement.public_url.clone();
+  if registry.heartbeat(announcement) {
+    tracing::info!(node_id, public_url, "Node is registered");
+  }
+
+  json!(registry.statuses())
+}
+
+#[handler]
+async fn registered_nodes(depot: &mut Depot) -> MResult<Json<Vec<NodeStatus>>> {
+  let registry = extract_registry(depot)?;
+  json!(registry.statuses())
+}
+
+/// Adds control routes accepting node heartbeats and starts the task forgetting lost nodes.
+pub(crate) fn start_registry(cluster: &ClusterConfig, registry: &Arc<NodeRegistry>, runtime: &mut ClusterRuntime) {
+  runtime.push_control(
+    Router::new()
+      .hoop(affix_state::inject(registry.clone()))
+      .push(Router::with_path("heartbeat").post(node_heartbeat))
+      .push(Router::with_path("nodes").get(registered_nodes)),
+  );
+
+  let registry = registry.clone();
+  let interval = cluster.heartbeat_interval();
+  runtime.spawn(async move {
+    let mut interval = tokio::time::interval(interval);
+    loop {
+      interval.tick().await;
+      for node_id in registry.prune() {
+        tracing::warn!(node_id, "Node is lost");
+      }
+    }
+  });
+}
+
+/// Spawns the heartbeat loop registering this node at the `target` control URL.
+pub(crate) fn spawn_heartbeat(runtime: &mut ClusterRuntime, cluster: ClusterConfig, target: String, hosts: Vec<String>) {
+  runtime.spawn(heartbeat_loop(cluster, target, hosts));
+}
+
+async fn heartbeat_loop(cluster: ClusterConfig, target: String, hosts: Vec<String>) {
+  let Some(public_url) = cluster.public_url.clone() else {
+    return;
+  };
+  let announcement = NodeAnnouncement {
+    node_id: cluster.node_id(),
+    public_url,
+    hosts,
+  };
+  let client = reqwest::Client::new();
+  let mut registered = false;
+  let mut interval = tokio::time::interval(cluster.heartbeat_interval());
+
+  loop {
+    interval.tick().await;
+    let result = control_request(
+      &client,
+      reqwest::Method::POST,
+      &target,
+      "/heartbeat",
+      cluster.secret.as_deref(),
+    )
+    .timeout(cluster.heartbeat_interval())
+    .json(&announcement)
+    .send()
+    .await
+    .and_then(|r| r.error_for_status());
+
+    match result {
+      Ok(_) if !registered => {
+        tracing::info!(target, node_id = announcement.node_id, "Registered at the node");
+        registered = true;
+      }
+      Ok(_) => tracing::trace!("Heartbeat is sent"),
+      Err(e) => {
+        tracing::warn!(target, error = ?e, "Can't send heartbeat to the node");
+        registered = false;
+      }
+    }
+  }
+}
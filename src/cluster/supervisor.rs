@@ -0,0 +1,374 @@
+use impulse_server_kit::prelude::*;
+use impulse_server_kit::salvo::affix_state;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use crate::cluster::{ClusterRuntime, control_request};
+use crate::config::{LbrpConfig, LbrpMode, ManagedNode, ProcessSpec, Service};
+
+/// Node state reported to the supervisor.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub(crate) struct NodeReport {
+  pub(crate) node_id: String,
+  pub(crate) lbrp_mode: LbrpMode,
+  pub(crate) services: Vec<String>,
+  /// Whether the node has a delivered config waiting for reload.
+  pub(crate) staged: bool,
+}
+
+struct ManagedNodeState {
+  node_id: String,
+  lbrp_mode: LbrpMode,
+  services: Vec<String>,
+  config_file: PathBuf,
+  /// Processes of the running config, keyed by service names.
+  processes: HashMap<String, ProcessSpec>,
+  /// Files and directories the running config reads or writes.
+  paths: BTreeSet<PathBuf>,
+  secret: Option<String>,
+  control_addr: Option<String>,
+  allow_remote_commands: bool,
+}
+
+impl ManagedNodeState {
+  fn staged_file(&self) -> PathBuf {
+    let mut path = self.config_file.as_os_str().to_owned();
+    path.push(".staged");
+    PathBuf::from(path)
+  }
+
+  /// Describes a setting of the delivered config which can run commands or touch files and is new or changed, if
+  /// that is not allowed.
+  fn forbidden_change(&self, config: &LbrpConfig) -> Option<String> {
+    if self.allow_remote_commands {
+      return None;
+    }
+    let cluster = config.cluster.clone().unwrap_or_default();
+    if cluster.secret != self.secret {
+      return Some("`cluster.secret`".to_string());
+    }
+    if cluster.control_addr != self.control_addr {
+      return Some("`cluster.control_addr`".to_string());
+    }
+    let command = config.common_services().find_map(|service| {
+      let spec = service.process_spec()?;
+      self
+        .processes
+        .get(&service.service_name)
+        .is_none_or(|current| !current.same_command(&spec))
+        .then_some(service.service_name.as_str())
+    });
+    if let Some(service) = command {
+      return Some(format!("Command of service `{service}`"));
+    }
+    config_paths(config)
+      .difference(&self.paths)
+      .next()
+      .map(|path| format!("Path `{}`", path.display()))
+  }
+
+  async fn report(&self) -> NodeReport {
+    NodeReport {
+      node_id: self.node_id.clone(),
+      lbrp_mode: self.lbrp_mode.clone(),
+      services: self.services.clone(),
+      staged: tokio::fs::try_exists(self.staged_file()).await.unwrap_or(false),
+    }
+  }
+}
+
+fn extract_node_state(depot: &Depot) -> MResult<&Arc<ManagedNodeState>> {
+  depot
+    .obtain::<Arc<ManagedNodeState>>()
+    .map_err(|_| ServerError::from_private_str("Can't get managed node state from depot!").with_500())
+}
+
+#[handler]
+#[tracing::instrument(skip_all, name = "stage-config")]
+async fn stage_config(depot: &mut Depot, req: &mut Request) -> MResult<Json<NodeReport>> {
+  let mut config = req.parse_json_simd::<LbrpConfig>().await.map_err(|e| {
+    ServerError::from_private(e)
+      .with_public("Invalid config slice!")
+      .with_400()
+  })?;
+  config.validate()?;
+  let state = extract_node_state(depot)?;
+  if config
+    .cluster
+    .as_ref()
+    .and_then(|c| c.allow_remote_commands)
+    .is_some_and(|allow| allow && !state.allow_remote_commands)
+  {
+    return Err(
+      ServerError::from_public("Remote commands can be allowed only in the local config of the node!").with_403(),
+    );
+  }
+  if let Some(setting) = state.forbidden_change(&config) {
+    tracing::warn!(setting, "Rejected config slice changing a local setting");
+    return Err(
+      ServerError::from_public(format!(
+        "{setting} can't be changed remotely, set `cluster.allow_remote_commands` on the node!"
+      ))
+      .with_403(),
+    );
+  }
+  // The applied slice replaces the local config, which must keep allowing what it allowed.
+  if let Some(cluster) = &mut config.cluster {
+    cluster.allow_remote_commands = Some(state.allow_remote_commands);
+  }
+
+  let data = serde_json::to_vec_pretty(&config).map_err(|e| ServerError::from_private(e).with_500())?;
+  tokio::fs::write(state.staged_file(), data)
+    .await
+    .map_err(|e| ServerError::from_private(e).with_500())?;
+  tracing::info!("Config slice is staged");
+
+  json!(state.report().await)
+}
+
+#[handler]
+#[tracing::instrument(skip_all, name = "apply-config")]
+async fn apply_config(depot: &mut Depot) -> MResult<Json<NodeReport>> {
+  let state = extract_node_state(depot)?;
+
+  let data = tokio::fs::read(state.staged_file()).await.map_err(|e| {
+    ServerError::from_private(e)
+      .with_public("There is no staged config!")
+      .with_404()
+  })?;
+  // Rewriting the config file in place triggers the config watcher.
+  tokio::fs::write(&state.config_file, data)
+    .await
+    .map_err(|e| ServerError::from_private(e).with_500())?;
+  tokio::fs::remove_file(state.staged_file())
+    .await
+    .map_err(|e| ServerError::from_private(e).with_500())?;
+  tracing::info!("Staged config is applied, reloading");
+
+  json!(state.report().await)
+}
+
+#[handler]
+async fn node_report(depot: &mut Depot) -> MResult<Json<NodeReport>> {
+  let state = extract_node_state(depot)?;
+  json!(state.report().await)
+}
+
+/// Adds control routes that let the supervisor deliver configs to this node.
+///
+/// Routes are added only if the cluster secret is set, so nobody else can reconfigure the node.
+pub(crate) fn start_managed_node(config: &LbrpConfig, config_file: &str, runtime: &mut ClusterRuntime) {
+  let Some(cluster) = &config.cluster else {
+    return;
+  };
+  if cluster.control_addr.is_none() {
+    return;
+  }
+  if cluster.secret.is_none() {
+    tracing::warn!("Cluster secret is not set, the node can't be managed by a supervisor");
+    return;
+  }
+
+  let state = Arc::new(ManagedNodeState {
+    node_id: cluster.node_id(),
+    lbrp_mode: config.lbrp_mode.clone(),
+    services: config.service_names(),
+    config_file: PathBuf::from(config_file),
+    processes: config
+      .common_services()
+      .filter_map(|s| Some((s.service_name.clone(), s.process_spec()?)))
+      .collect(),
+    paths: config_paths(config),
+    secret: cluster.secret.clone(),
+    control_addr: cluster.control_addr.clone(),
+    allow_remote_commands: cluster.allow_remote_commands.is_some_and(|v| v),
+  });
+  runtime.push_control(
+    Router::new()
+      .hoop(affix_state::inject(state))
+      .push(Router::with_path("config").put(stage_config))
+      .push(Router::with_path("reload").post(apply_config))
+      .push(Router::with_path("status").get(node_report)),
+  );
+}
+
+/// Returns files and directories the config reads or writes, including log files of services.
+fn config_paths(config: &LbrpConfig) -> BTreeSet<PathBuf> {
+  let mut paths = BTreeSet::new();
+  for service in &config.services {
+    match service {
+      Service::ErrorHandler(err_handler) => {
+        paths.insert(err_handler.dist_dir.clone());
+      }
+      Service::CommonStatic(statics) => {
+        paths.insert(statics.path.clone());
+      }
+      Service::CommonService(service) => {
+        if let Some(dir) = service.logs.as_ref().and_then(|logs| logs.dir.as_ref()) {
+          paths.insert(dir.join(format!("{}.log", service.service_name)));
+        }
+      }
+      Service::TcpStream(service) | Service::UdpStream(service) => {
+        for certificate in service.tls.iter().flat_map(|tls| &tls.certificates) {
+          paths.insert(certificate.cert.clone());
+          paths.insert(certificate.key.clone());
+        }
+      }
+    }
+  }
+  paths
+}
+
+/// Builds the config the node should run with from the master config.
+fn node_slice(master: &LbrpConfig, node: &ManagedNode) -> LbrpConfig {
+  let mut cluster = node.cluster.clone();
+  if cluster.secret.is_none() {
+    cluster.secret = master.cluster.as_ref().and_then(|c| c.secret.clone());
+  }
+  if cluster.node_id.is_none() {
+    cluster.node_id = Some(node.node_id.clone());
+  }
+
+  LbrpConfig {
+    lbrp_mode: node.lbrp_mode.clone(),
+    services: master
+      .services
+      .iter()
+      .filter(|s| match s {
+        Service::CommonService(service) => node
+          .services
+          .as_ref()
+          .is_none_or(|names| names.contains(&service.service_name)),
+        Service::TcpStream(service) | Service::UdpStream(service) => node
+          .services
+          .as_ref()
+          .is_none_or(|names| names.contains(&service.service_name)),
+        _ => true,
+      })
+      .cloned()
+      .collect(),
+    cors_opts: master.cors_opts.clone(),
+    cluster: Some(cluster),
+    trusted_proxies: master.trusted_proxies.clone(),
+    proxy_protocol: master.proxy_protocol.clone(),
+    admin_secret: master.admin_secret.clone(),
+  }
+}
+
+struct Supervisor<'a> {
+  master: &'a LbrpConfig,
+  nodes: &'a [ManagedNode],
+  secret: Option<&'a str>,
+  client: reqwest::Client,
+}
+
+impl Supervisor<'_> {
+  /// Secret of the node, the same one its slice carries; the master one is used if the node has none.
+  fn node_secret<'a>(&'a self, node: &'a ManagedNode) -> Option<&'a str> {
+    node.cluster.secret.as_deref().or(self.secret)
+  }
+
+  async fn stage(&self, node: &ManagedNode) -> Result<NodeReport, reqwest::Error> {
+    control_request(&self.client, reqwest::Method::PUT, &node.control_url, "/config", self.node_secret(node))
+      .json(&node_slice(self.master, node))
+      .send()
+      .await?
+      .error_for_status()?
+      .json::<NodeReport>()
+      .await
+  }
+
+  async fn apply(&self, node: &ManagedNode) -> Result<NodeReport, reqwest::Error> {
+    control_request(&self.client, reqwest::Method::POST, &node.control_url, "/reload", self.node_secret(node))
+      .send()
+      .await?
+      .error_for_status()?
+      .json::<NodeReport>()
+      .await
+  }
+
+  async fn status(&self, node: &ManagedNode) -> Result<NodeReport, reqwest::Error> {
+    control_request(&self.client, reqwest::Method::GET, &node.control_url, "/status", self.node_secret(node))
+      .send()
+      .await?
+      .error_for_status()?
+      .json::<NodeReport>()
+      .await
+  }
+
+  /// Stages config slices on the given nodes and reloads them only if every node has accepted its slice.
+  async fn deliver(&self, nodes: &[&ManagedNode]) -> bool {
+    let staged = futures_util::future::join_all(nodes.iter().map(|node| self.stage(node))).await;
+    let mut all_staged = true;
+    for (node, result) in nodes.iter().zip(staged) {
+      if let Err(e) = result {
+        tracing::error!(node_id = node.node_id, error = ?e, "Can't deliver config slice");
+        all_staged = false;
+      }
+    }
+    if !all_staged {
+      tracing::error!("Config is not delivered to every node, reload is cancelled");
+      return false;
+    }
+
+    let applied = futures_util::future::join_all(nodes.iter().map(|node| self.apply(node))).await;
+    for (node, result) in nodes.iter().zip(applied) {
+      match result {
+        Ok(_) => tracing::info!(node_id = node.node_id, "Node is reloading with the new config"),
+        Err(e) => tracing::error!(node_id = node.node_id, error = ?e, "Can't reload node"),
+      }
+    }
+    true
+  }
+}
+
+/// Delivers config slices to managed nodes and watches their status until the supervisor's config is reloaded.
+pub(crate) async fn supervise(master: &LbrpConfig) {
+  let cluster = master.cluster.clone().unwrap_or_default();
+  let nodes = cluster.nodes.clone().unwrap_or_default();
+  let supervisor = Supervisor {
+    master,
+    nodes: &nodes,
+    secret: cluster.secret.as_deref(),
+    client: reqwest::Client::builder()
+      .timeout(cluster.heartbeat_timeout())
+      .build()
+      .unwrap(),
+  };
+
+  let all_nodes = supervisor.nodes.iter().collect::<Vec<_>>();
+  let mut delivered = supervisor.deliver(&all_nodes).await;
+  let mut reachable = HashMap::<&str, bool>::new();
+  let mut interval = tokio::time::interval(cluster.heartbeat_interval());
+
+  loop {
+    interval.tick().await;
+
+    let reports = futures_util::future::join_all(supervisor.nodes.iter().map(|node| supervisor.status(node))).await;
+    let mut returned = vec![];
+    for (node, report) in supervisor.nodes.iter().zip(reports) {
+      let was_reachable = reachable.insert(node.node_id.as_str(), report.is_ok());
+      match report {
+        Ok(report) => {
+          tracing::debug!(node_id = node.node_id, ?report, "Node status");
+          if was_reachable == Some(false) {
+            tracing::info!(node_id = node.node_id, "Node is reachable again");
+            returned.push(node);
+          }
+        }
+        Err(e) if was_reachable != Some(false) => {
+          tracing::warn!(node_id = node.node_id, error = ?e, "Node is unreachable");
+        }
+        Err(_) => {}
+      }
+    }
+
+    if !delivered {
+      delivered = supervisor.deliver(&all_nodes).await;
+    } else if !returned.is_empty() {
+      supervisor.deliver(&returned).await;
+    }
+  }
+}
@@ -0,0 +1,90 @@
+use impulse_server_kit::prelude::*;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tokio::sync::Mutex;
+
+use crate::cluster::{ClusterRuntime, NodeBalancer};
+use crate::cluster::registry::{NodeRegistry, spawn_heartbeat, start_registry};
+use crate::config::{CommonService, LbrpConfig};
+use crate::error_handling::GatewayErrors;
+use crate::process_management::ManagedProcesses;
+use crate::router::{BuildMode, build_router};
+
+/// Services the older brother runs by itself while no younger brother is alive.
+struct FallbackServices {
+  services: Vec<CommonService>,
+  processes: Mutex<ManagedProcesses>,
+  running: AtomicBool,
+}
+
+impl FallbackServices {
+  fn new(config: &LbrpConfig) -> Self {
+    Self {
+      services: config.common_services().cloned().collect(),
+      processes: Mutex::new(ManagedProcesses::default()),
+      running: AtomicBool::new(false),
+    }
+  }
+
+  async fn start(&self) {
+    self.processes.lock().await.sync(self.services.iter()).await;
+  }
+
+  async fn stop(&self) {
+    self.processes.lock().await.stop_all().await;
+  }
+}
+
+/// Starts own services when no younger brother is alive and stops them when brothers are back.
+async fn fallback_supervisor(registry: Arc<NodeRegistry>, fallback: Arc<FallbackServices>, period: Duration) {
+  // Gives younger brothers one heartbeat period to register after the boot.
+  let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+  loop {
+    interval.tick().await;
+    let alive = registry.alive_count();
+    if alive == 0 && !fallback.running.swap(true, Ordering::Relaxed) {
+      tracing::warn!("No younger brother is alive, starting own services");
+      fallback.start().await;
+    } else if alive > 0 && fallback.running.swap(false, Ordering::Relaxed) {
+      tracing::info!(alive, "Younger brothers are back, stopping own services");
+      fallback.stop().await;
+    }
+  }
+}
+
+#[handler]
+async fn not_found(res: &mut Response) {
+  res.status_code(StatusCode::NOT_FOUND);
+}
+
+/// Starts accepting younger brothers' registrations and returns the router offloading requests to them.
+pub(crate) async fn start_older_brother(config: &LbrpConfig, runtime: &mut ClusterRuntime) -> Router {
+  let cluster = config.cluster.clone().unwrap_or_default();
+  let registry = Arc::new(NodeRegistry::new(cluster.heartbeat_timeout()));
+  start_registry(&cluster, &registry, runtime);
+
+  let fallback = Arc::new(FallbackServices::new(config));
+  runtime.spawn(fallback_supervisor(
+    registry.clone(),
+    fallback,
+    cluster.heartbeat_interval(),
+  ));
+  older_brother_router(config, registry, BuildMode::Serve).await
+}
+
+/// Router balancing over younger brothers of the `registry` and falling back to own services.
+pub(crate) async fn older_brother_router(config: &LbrpConfig, registry: Arc<NodeRegistry>, mode: BuildMode) -> Router {
+  Router::new()
+    .hoop(GatewayErrors)
+    .hoop(NodeBalancer::new(registry, true))
+    .push(build_router(config, mode).await)
+    .push(Router::with_path("{**rest_path}").goal(not_found))
+}
+
+/// Spawns the younger brother's heartbeat loop registering it at the older brother.
+pub(crate) fn spawn_younger_heartbeat(config: &LbrpConfig, runtime: &mut ClusterRuntime) {
+  let cluster = config.cluster.clone().unwrap_or_default();
+  let older_brother = cluster.older_brother.clone().unwrap_or_default();
+  spawn_heartbeat(runtime, cluster, older_brother, config.hosts());
+}
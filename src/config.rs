@@ -1,8 +1,9 @@
 use impulse_server_kit::prelude::*;
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
 
-#[derive(Deserialize, Serialize, Default)]
+#[derive(Deserialize, Serialize, Default, Clone, Debug)]
 pub(crate) enum LbrpMode {
   /// Сам принимает все соединения и управляет сервисами.
   #[default]
@@ -15,7 +16,7 @@ pub(crate) enum LbrpMode {
   Ybob(LbrpYBOBMode),
 }
 
-#[derive(Deserialize, Serialize)]
+#[derive(Deserialize, Serialize, Clone, Debug)]
 pub(crate) enum LbrpPCMode {
   /// Родитель: принимает все соединения и перенаправляет их детям. Просто балансировщик нагрузки.
   Parent,
@@ -23,7 +24,7 @@ pub(crate) enum LbrpPCMode {
   Child,
 }
 
-#[derive(Deserialize, Serialize)]
+#[derive(Deserialize, Serialize, Clone, Debug)]
 pub(crate) enum LbrpYBOBMode {
   /// Старший брат: любит скидывать всю работу на младших братьев, но также владеет сервисами на случай, если младших братьев не будет рядом.
   OlderBrother,
@@ -31,43 +32,434 @@ pub(crate) enum LbrpYBOBMode {
   YoungerBrother,
 }
 
-#[derive(Deserialize, Serialize)]
+/// Cluster settings for the `PC`, `Ybob` and `Supervisor` modes.
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct ClusterConfig {
+  /// Unique name of the node; `public_url` is used if not specified.
+  pub(crate) node_id: Option<String>,
+  /// Address of the cluster control server of this node, e.g. `0.0.0.0:19900`.
+  pub(crate) control_addr: Option<String>,
+  /// URL other nodes use to proxy requests to this node, e.g. `http://127.0.0.1:8081`.
+  pub(crate) public_url: Option<String>,
+  /// Control URL of the parent node, e.g. `http://127.0.0.1:19900`.
+  pub(crate) parent: Option<String>,
+  /// Control URL of the older brother, for `YoungerBrother` nodes.
+  pub(crate) older_brother: Option<String>,
+  /// Shared secret required on every control request.
+  pub(crate) secret: Option<String>,
+  /// Whether configs delivered by the supervisor may add or change commands of service processes; disabled by
+  /// default, because it lets anybody with the secret run any command on the node. Only the local config can enable it.
+  pub(crate) allow_remote_commands: Option<bool>,
+  /// Seconds between heartbeats (default 5).
+  pub(crate) heartbeat_interval: Option<u64>,
+  /// Seconds without heartbeats after which a node is considered lost (default 15).
+  pub(crate) heartbeat_timeout: Option<u64>,
+  /// Nodes managed by the supervisor.
+  pub(crate) nodes: Option<Vec<ManagedNode>>,
+}
+
+/// Node managed by the supervisor and its slice of the master config.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct ManagedNode {
+  pub(crate) node_id: String,
+  /// Control URL of the node, e.g. `http://10.0.0.2:19900`.
+  pub(crate) control_url: String,
+  /// Mode the node runs in (default `Single`).
+  #[serde(default)]
+  pub(crate) lbrp_mode: LbrpMode,
+  /// Names of the common and stream services delivered to the node; all services if not specified.
+  pub(crate) services: Option<Vec<String>>,
+  /// Cluster settings of the node; `control_addr` is required to keep the node managed.
+  pub(crate) cluster: ClusterConfig,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
 #[serde(tag = "type", rename_all = "snake_case")]
 #[allow(clippy::enum_variant_names)]
 pub(crate) enum Service {
   ErrorHandler(ErrorHandler),
   CommonService(CommonService),
   CommonStatic(CommonStatic),
+  TcpStream(StreamService),
+  UdpStream(StreamService),
 }
 
 #[derive(Deserialize, Serialize, Clone)]
+#[serde(deny_unknown_fields)]
 pub(crate) struct ErrorHandler {
   pub(crate) dist_dir: PathBuf,
   pub(crate) static_files: Vec<String>,
 }
 
-#[derive(Deserialize, Serialize)]
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(deny_unknown_fields)]
 pub(crate) struct CommonService {
   pub(crate) service_name: String,
   #[cfg(feature = "authnz")]
   pub(crate) require_subdomain_auth: Option<Vec<authnz_server_sdk::authnz_common::AccessTag>>,
+  /// Accepted and ignored without the `authnz` feature, so configs stay valid for both builds.
+  #[cfg(not(feature = "authnz"))]
+  #[serde(default, rename = "require_subdomain_auth", skip_serializing)]
+  _require_subdomain_auth: Option<serde::de::IgnoredAny>,
   pub(crate) startup_cmd: Option<PathBuf>,
+  pub(crate) startup_args: Option<Vec<String>>,
   pub(crate) working_dir: Option<PathBuf>,
+  /// Environment variables of the process.
+  pub(crate) env: Option<BTreeMap<String, String>>,
+  /// File with `KEY=VALUE` lines added to the environment, e.g. with secrets; `env` overrides it.
+  pub(crate) env_file: Option<PathBuf>,
+  /// User name or id to run the process as.
+  pub(crate) user: Option<String>,
+  /// Group name or id to run the process as; the primary group of `user` if not specified.
+  pub(crate) group: Option<String>,
+  /// Octal file mode creation mask of the process, e.g. `"027"`.
+  pub(crate) umask: Option<String>,
+  /// Seconds to wait after the process is started before its readiness is probed.
   pub(crate) wait_after: Option<u64>,
+  /// Seconds to wait for the readiness after `wait_after` before the process is considered failed (default 30).
+  pub(crate) ready_timeout: Option<u64>,
+  pub(crate) restart: Option<RestartPolicy>,
+  /// Names of managed services which must be ready before this one is started.
+  pub(crate) depends_on: Option<Vec<String>>,
+  pub(crate) readiness: Option<ReadinessProbe>,
+  /// Seconds between `SIGTERM` and `SIGKILL` when the process is stopped (default 10).
+  pub(crate) stop_timeout: Option<u64>,
+  pub(crate) logs: Option<ServiceLogs>,
   pub(crate) from: String,
-  pub(crate) to: String,
+  /// Path prefix of requests served by the service, e.g. `/v1`; the whole host if not specified.
+  pub(crate) path_prefix: Option<String>,
+  /// Regex the whole request path must also match.
+  pub(crate) path_regex: Option<String>,
+  /// Whether `path_prefix` is stripped from the path before forwarding (default `false`).
+  pub(crate) strip_path_prefix: Option<bool>,
+  /// Rewrite, redirect and fixed response rules applied in order before proxying.
+  pub(crate) rules: Option<Vec<Rule>>,
+  pub(crate) to: UpstreamList,
+  pub(crate) balancing: Option<BalancingStrategy>,
+  pub(crate) health_check: Option<HealthCheck>,
+  pub(crate) circuit_breaker: Option<CircuitBreaker>,
+  /// Seconds to establish a connection to an upstream; unlimited if not specified.
+  pub(crate) connect_timeout: Option<u64>,
+  /// Seconds to receive response headers from an upstream; unlimited if not specified.
+  pub(crate) request_timeout: Option<u64>,
+  /// Seconds without data from an upstream while the response body is streamed; unlimited if not specified.
+  pub(crate) idle_timeout: Option<u64>,
+  /// Maximum size of proxied request bodies in bytes, `413` if exceeded; unlimited if not specified.
+  pub(crate) max_body_size: Option<u64>,
+  pub(crate) retry: Option<RetryPolicy>,
+  pub(crate) connection_pool: Option<ConnectionPool>,
   pub(crate) cors_domains: Option<Vec<String>>,
   pub(crate) skip_err_handling: Option<bool>,
   pub(crate) provide_ip_as_header: Option<String>,
+  /// PROXY protocol header sent to upstreams on every new connection; `http` upstreams only.
+  pub(crate) send_proxy_protocol: Option<ProxyProtocolVersion>,
+  /// Whether `Forwarded`, `X-Forwarded-For`/`-Proto`/`-Host`/`-Port` and `X-Real-IP` are provided (default `false`).
+  pub(crate) forwarded_headers: Option<bool>,
+  /// Operations on headers of requests before they are proxied.
+  pub(crate) request_headers: Option<HeaderOps>,
+  /// Operations on headers of responses, including the ones made by `lbrp` itself.
+  pub(crate) response_headers: Option<HeaderOps>,
+}
+
+/// Restarting of an exited service process.
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct RestartPolicy {
+  #[serde(default)]
+  pub(crate) when: RestartWhen,
+  /// Restarts in a row after which the service is given up; unlimited if not specified.
+  pub(crate) max_restarts: Option<u32>,
+  /// Delay before the first restart in milliseconds, doubled on every next one (default 500).
+  pub(crate) backoff: Option<u64>,
+  /// Maximum delay between restarts in seconds (default 60).
+  pub(crate) max_backoff: Option<u64>,
+  /// Seconds of uptime after which the process isn't considered crashing, so restarts are counted anew (default 60).
+  pub(crate) reset_after: Option<u64>,
+}
+
+/// How to find out that a started service process is ready; TCP connect to every upstream by default.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub(crate) enum ReadinessProbe {
+  /// Connect to the address, e.g. `127.0.0.1:8020`; to every upstream if not specified.
+  Tcp { addr: Option<String> },
+  /// `GET` the path at the first upstream until it responds with `2xx`.
+  Http { path: String },
+  /// Wait for an output line matching the regex.
+  LogLine { pattern: String },
+}
+
+/// Capturing of the service process output; it is always traced.
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct ServiceLogs {
+  /// Directory for `<service_name>.log` files; output is not written to files if not specified.
+  pub(crate) dir: Option<PathBuf>,
+  /// Size of the log file in bytes after which it is rotated (default 10 MiB).
+  pub(crate) max_size: Option<u64>,
+  /// Rotated log files to keep (default 5).
+  pub(crate) max_files: Option<u32>,
+  /// Last lines kept in memory for the logs endpoint (default 200).
+  pub(crate) tail_lines: Option<usize>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum RestartWhen {
+  Always,
+  #[default]
+  OnFailure,
+  Never,
+}
+
+/// Rule of a service: the action is applied to requests matching every condition.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Rule {
+  #[serde(default)]
+  pub(crate) r#match: RuleMatch,
+  pub(crate) action: RuleAction,
 }
 
-#[derive(Deserialize, Serialize)]
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct RuleMatch {
+  /// Regex the path must match; its groups are available in actions as `$1`, `$name`.
+  pub(crate) path: Option<String>,
+  pub(crate) methods: Option<Vec<String>>,
+  /// Regexes values of the headers must match.
+  pub(crate) headers: Option<BTreeMap<String, String>>,
+  /// Regexes values of the query parameters must match.
+  pub(crate) query: Option<BTreeMap<String, String>>,
+}
+
+/// Action of a rule; path changes go on to the next rules, redirects and responses finish the request.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub(crate) enum RuleAction {
+  /// Replaces the part of the path matched by `match.path`, or the whole path without it.
+  Rewrite {
+    path: String,
+  },
+  AddPrefix {
+    prefix: String,
+  },
+  StripPrefix {
+    prefix: String,
+  },
+  /// Redirects with `301`, `302` (default), `307` or `308`.
+  Redirect {
+    location: String,
+    status: Option<u16>,
+  },
+  Respond {
+    status: u16,
+    body: Option<String>,
+    content_type: Option<String>,
+  },
+}
+
+/// Header operations applied as `remove`, `set` and then `append`.
+///
+/// Values may contain `{client_ip}`, `{host}`, `{request_id}` and `{scheme}` variables.
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct HeaderOps {
+  pub(crate) set: Option<BTreeMap<String, String>>,
+  pub(crate) append: Option<BTreeMap<String, String>>,
+  pub(crate) remove: Option<Vec<String>>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ProxyProtocolVersion {
+  V1,
+  V2,
+}
+
+/// Listener accepting PROXY protocol headers, e.g. behind a TCP load balancer.
+///
+/// Connections are relayed to `forward_to`, one of `lbrp` own listeners, with the client address remembered.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct ProxyProtocolListener {
+  /// Address to listen on, e.g. `0.0.0.0:8443`.
+  pub(crate) listen: String,
+  /// Address of the `lbrp` listener connections are relayed to, e.g. `127.0.0.1:443`.
+  pub(crate) forward_to: String,
+  /// Addresses or networks allowed to send PROXY protocol headers; others are relayed as is.
+  pub(crate) trusted: Vec<String>,
+}
+
+/// Service forwarding raw TCP connections or UDP datagrams from its own port to upstreams.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct StreamService {
+  pub(crate) service_name: String,
+  /// Address to listen on, e.g. `0.0.0.0:5432`.
+  pub(crate) listen: String,
+  /// Upstream addresses, e.g. `10.0.0.5:5432`; TCP upstreams may also be `unix:` sockets.
+  pub(crate) to: UpstreamList,
+  pub(crate) balancing: Option<BalancingStrategy>,
+  pub(crate) circuit_breaker: Option<CircuitBreaker>,
+  /// Seconds to establish a connection to an upstream; unlimited if not specified.
+  pub(crate) connect_timeout: Option<u64>,
+  /// Seconds without data in either direction after which a connection is closed; unlimited for TCP and 60 for UDP
+  /// if not specified.
+  pub(crate) idle_timeout: Option<u64>,
+  /// UDP sessions served at once; datagrams from new clients are dropped while it is reached (default 1024).
+  pub(crate) max_sessions: Option<usize>,
+  /// TLS termination of TCP connections; TLS is forwarded as is if not specified.
+  pub(crate) tls: Option<StreamTls>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct StreamTls {
+  /// Certificates chosen by the server name (SNI) of the client; the first one is used if none matches.
+  pub(crate) certificates: Vec<TlsCertificate>,
+  /// Upstreams chosen by the server name; other connections go to `to` of the service.
+  pub(crate) routes: Option<Vec<SniRoute>>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct TlsCertificate {
+  /// Server names pattern in the `from` syntax, e.g. `db.example.com` or `*.example.com`.
+  pub(crate) sni: String,
+  /// PEM file with the certificate chain.
+  pub(crate) cert: PathBuf,
+  /// PEM file with the private key.
+  pub(crate) key: PathBuf,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct SniRoute {
+  /// Server names pattern in the `from` syntax.
+  pub(crate) sni: String,
+  pub(crate) to: UpstreamList,
+  pub(crate) balancing: Option<BalancingStrategy>,
+}
+
+/// Upstream address of a service, optionally with its weight for the weighted balancing.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(untagged)]
+pub(crate) enum Upstream {
+  Url(String),
+  Weighted { url: String, weight: u32 },
+}
+
+/// One or several upstreams behind the same `from` host.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(untagged)]
+pub(crate) enum UpstreamList {
+  Single(Upstream),
+  Multiple(Vec<Upstream>),
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq, Eq, Hash)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(crate) enum BalancingStrategy {
+  #[default]
+  RoundRobin,
+  Weighted,
+  LeastConnections,
+  Random,
+  ConsistentHash {
+    by: HashKey,
+  },
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum HashKey {
+  ClientIp,
+  Cookie(String),
+}
+
+/// Active health checking of service upstreams.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq, Hash)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct HealthCheck {
+  /// Path to probe, e.g. `/health`.
+  pub(crate) path: String,
+  /// Interval between probes in seconds (default 10).
+  pub(crate) interval: Option<u64>,
+  /// Probe timeout in milliseconds (default 2000).
+  pub(crate) timeout: Option<u64>,
+  /// Consecutive successful probes to mark an upstream up (default 2).
+  pub(crate) healthy_threshold: Option<u32>,
+  /// Consecutive failed probes to mark an upstream down (default 3).
+  pub(crate) unhealthy_threshold: Option<u32>,
+  /// Expected response status; any `2xx` if not specified.
+  pub(crate) expected_status: Option<u16>,
+}
+
+/// Passive health checking: ejects an upstream after consecutive failures.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq, Hash)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct CircuitBreaker {
+  /// Consecutive connection errors, timeouts or `5xx` responses to open the circuit (default 5).
+  pub(crate) failure_threshold: Option<u32>,
+  /// Seconds to keep the upstream ejected before a trial request (default 30).
+  pub(crate) cool_down: Option<u64>,
+}
+
+/// Connections to upstreams of a service; they are kept across reloads while upstreams and settings are the same.
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq, Eq, Hash)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct ConnectionPool {
+  /// Idle connections kept per upstream host; unlimited if not specified.
+  pub(crate) max_idle_per_host: Option<usize>,
+  /// Seconds to keep an idle connection open (default 90).
+  pub(crate) idle_keep_alive: Option<u64>,
+  /// Whether HTTP/2 is used without negotiation, e.g. h2c toward `http` upstreams (default `false`).
+  pub(crate) http2_prior_knowledge: Option<bool>,
+  /// Seconds between TCP keepalive probes; disabled if not specified.
+  pub(crate) tcp_keepalive: Option<u64>,
+  /// Whether Nagle's algorithm is disabled (default `true`).
+  pub(crate) tcp_nodelay: Option<bool>,
+}
+
+/// Retrying of requests which failed to get a response from an upstream.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct RetryPolicy {
+  /// Retries after the first attempt (default 1).
+  pub(crate) attempts: Option<u32>,
+  /// Delay before the first retry in milliseconds, doubled on every next one (default 50).
+  pub(crate) backoff: Option<u64>,
+  /// Upstream response statuses to retry on (default `[502, 503, 504]`).
+  pub(crate) on_statuses: Option<Vec<u16>>,
+  /// Failures to retry on (default all of them).
+  pub(crate) on_errors: Option<Vec<RetryOn>>,
+  /// Whether only `GET`, `HEAD`, `OPTIONS`, `PUT`, `DELETE` and `TRACE` requests are retried (default `true`).
+  pub(crate) idempotent_only: Option<bool>,
+  /// Whether retries are sent to the next available upstream (default `true`).
+  pub(crate) next_upstream: Option<bool>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum RetryOn {
+  /// Connection refused, DNS resolution and other connection failures.
+  Connect,
+  /// `connect_timeout` or `request_timeout` is exceeded.
+  Timeout,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(deny_unknown_fields)]
 pub(crate) struct CommonStatic {
   pub(crate) path: PathBuf,
   pub(crate) static_routes: Vec<String>,
 }
 
 #[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
 pub(crate) struct CorsOpts {
   pub(crate) allowed_methods: String,
   pub(crate) allowed_client_headers: String,
@@ -84,80 +476,387 @@ impl Default for CorsOpts {
   }
 }
 
-#[derive(Deserialize, Serialize, Default)]
+#[derive(Deserialize, Serialize, Default, Clone)]
+#[serde(deny_unknown_fields)]
 pub(crate) struct LbrpConfig {
   pub(crate) lbrp_mode: LbrpMode,
   pub(crate) services: Vec<Service>,
   pub(crate) cors_opts: CorsOpts,
+  pub(crate) cluster: Option<ClusterConfig>,
+  /// Addresses or networks like `10.0.0.0/8` of proxies whose forwarding headers are kept.
+  pub(crate) trusted_proxies: Option<Vec<String>>,
+  /// Additional listener accepting PROXY protocol headers.
+  pub(crate) proxy_protocol: Option<ProxyProtocolListener>,
+  /// Secret of the admin routes, e.g. service logs; they aren't served without it.
+  pub(crate) admin_secret: Option<String>,
 }
 
-impl CommonService {
-  pub(crate) fn should_startup(&self) -> bool {
-    self.startup_cmd.is_some() && self.working_dir.is_some()
-  }
-
-  pub(crate) fn startup(&self) -> MResult<std::process::Child> {
-    use std::process::Command;
-
-    let startup_cmd = self
-      .startup_cmd
-      .as_ref()
-      .ok_or(ServerError::from_public("There is no `startup_cmd` specified!").with_500())?;
-    let working_dir = self
-      .working_dir
-      .as_ref()
-      .ok_or(ServerError::from_public("There is no `working_dir` specified!").with_500())?;
-
-    let child = Command::new(startup_cmd)
-      .current_dir(working_dir)
-      .stdout(std::process::Stdio::piped())
-      .stderr(std::process::Stdio::piped())
-      .spawn()
-      .map_err(|e| ServerError::from_private(e).with_500())?;
-
-    if let Some(wait_after) = self.wait_after {
-      std::thread::sleep(std::time::Duration::from_secs(wait_after));
+impl Upstream {
+  pub(crate) fn url(&self) -> &str {
+    match self {
+      Self::Url(url) => url,
+      Self::Weighted { url, .. } => url,
     }
+  }
 
-    Ok(child)
+  pub(crate) fn weight(&self) -> u32 {
+    match self {
+      Self::Url(_) => 1,
+      Self::Weighted { weight, .. } => *weight,
+    }
+  }
+}
+
+impl UpstreamList {
+  pub(crate) fn iter(&self) -> std::slice::Iter<'_, Upstream> {
+    match self {
+      Self::Single(upstream) => std::slice::from_ref(upstream).iter(),
+      Self::Multiple(upstreams) => upstreams.iter(),
+    }
+  }
+}
+
+impl HealthCheck {
+  pub(crate) fn interval(&self) -> std::time::Duration {
+    std::time::Duration::from_secs(self.interval.unwrap_or(10).max(1))
+  }
+
+  pub(crate) fn timeout(&self) -> std::time::Duration {
+    std::time::Duration::from_millis(self.timeout.unwrap_or(2000).max(1))
+  }
+
+  pub(crate) fn healthy_threshold(&self) -> u32 {
+    self.healthy_threshold.unwrap_or(2).max(1)
+  }
+
+  pub(crate) fn unhealthy_threshold(&self) -> u32 {
+    self.unhealthy_threshold.unwrap_or(3).max(1)
+  }
+
+  pub(crate) fn is_expected(&self, status: u16) -> bool {
+    match self.expected_status {
+      Some(expected) => status == expected,
+      None => (200..300).contains(&status),
+    }
+  }
+}
+
+impl CircuitBreaker {
+  pub(crate) fn failure_threshold(&self) -> u32 {
+    self.failure_threshold.unwrap_or(5).max(1)
+  }
+
+  pub(crate) fn cool_down(&self) -> std::time::Duration {
+    std::time::Duration::from_secs(self.cool_down.unwrap_or(30))
+  }
+}
+
+impl RetryPolicy {
+  pub(crate) fn attempts(&self) -> u32 {
+    self.attempts.unwrap_or(1)
+  }
+
+  /// Delay before the retry number `retry`, starting from 1.
+  pub(crate) fn backoff(&self, retry: u32) -> std::time::Duration {
+    std::time::Duration::from_millis(self.backoff.unwrap_or(50)).saturating_mul(1 << retry.saturating_sub(1).min(16))
+  }
+
+  pub(crate) fn retries_status(&self, status: u16) -> bool {
+    self
+      .on_statuses
+      .as_deref()
+      .unwrap_or(&[502, 503, 504])
+      .contains(&status)
+  }
+
+  pub(crate) fn retries_error(&self, error: RetryOn) -> bool {
+    self.on_errors.as_ref().is_none_or(|errors| errors.contains(&error))
+  }
+
+  pub(crate) fn allows_method(&self, method: &str) -> bool {
+    !self.idempotent_only.unwrap_or(true) || matches!(method, "GET" | "HEAD" | "OPTIONS" | "PUT" | "DELETE" | "TRACE")
+  }
+
+  pub(crate) fn next_upstream(&self) -> bool {
+    self.next_upstream.unwrap_or(true)
+  }
+}
+
+impl ClusterConfig {
+  pub(crate) fn node_id(&self) -> String {
+    self
+      .node_id
+      .clone()
+      .or(self.public_url.clone())
+      .or(self.control_addr.clone())
+      .unwrap_or("lbrp".to_string())
+  }
+
+  pub(crate) fn heartbeat_interval(&self) -> std::time::Duration {
+    std::time::Duration::from_secs(self.heartbeat_interval.unwrap_or(5).max(1))
+  }
+
+  pub(crate) fn heartbeat_timeout(&self) -> std::time::Duration {
+    std::time::Duration::from_secs(self.heartbeat_timeout.unwrap_or(15).max(1))
+  }
+}
+
+/// Everything that affects the process of a service; the process is restarted on reload only if this changes.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct ProcessSpec {
+  pub(crate) startup_cmd: PathBuf,
+  pub(crate) startup_args: Vec<String>,
+  pub(crate) working_dir: PathBuf,
+  pub(crate) env: BTreeMap<String, String>,
+  pub(crate) env_file: Option<PathBuf>,
+  pub(crate) user: Option<String>,
+  pub(crate) group: Option<String>,
+  pub(crate) umask: Option<String>,
+  /// Upstream URLs the process is expected to listen on.
+  pub(crate) upstreams: Vec<String>,
+  pub(crate) readiness: Option<ReadinessProbe>,
+  pub(crate) wait_after: std::time::Duration,
+  pub(crate) ready_timeout: std::time::Duration,
+  pub(crate) restart: RestartPolicy,
+  pub(crate) stop_timeout: std::time::Duration,
+  pub(crate) logs: ServiceLogs,
+}
+
+impl ProcessSpec {
+  /// Whether both run the same command in the same environment as the same user.
+  pub(crate) fn same_command(&self, other: &Self) -> bool {
+    (
+      &self.startup_cmd,
+      &self.startup_args,
+      &self.working_dir,
+      &self.env,
+      &self.env_file,
+      &self.user,
+      &self.group,
+      &self.umask,
+    ) == (
+      &other.startup_cmd,
+      &other.startup_args,
+      &other.working_dir,
+      &other.env,
+      &other.env_file,
+      &other.user,
+      &other.group,
+      &other.umask,
+    )
+  }
+}
+
+impl RestartPolicy {
+  pub(crate) fn should_restart(&self, status: Option<&std::process::ExitStatus>) -> bool {
+    match self.when {
+      RestartWhen::Always => true,
+      RestartWhen::OnFailure => status.is_none_or(|s| !s.success()),
+      RestartWhen::Never => false,
+    }
+  }
+
+  pub(crate) fn max_backoff(&self) -> std::time::Duration {
+    std::time::Duration::from_secs(self.max_backoff.unwrap_or(60))
+  }
+
+  pub(crate) fn reset_after(&self) -> std::time::Duration {
+    std::time::Duration::from_secs(self.reset_after.unwrap_or(60))
+  }
+
+  /// Delay before the restart with the given number of restarts in a row before it.
+  pub(crate) fn backoff(&self, restarts: u32) -> std::time::Duration {
+    let backoff = std::time::Duration::from_millis(self.backoff.unwrap_or(500));
+    backoff
+      .saturating_mul(2u32.saturating_pow(restarts))
+      .min(self.max_backoff())
+  }
+}
+
+impl ServiceLogs {
+  pub(crate) fn max_size(&self) -> u64 {
+    self.max_size.unwrap_or(10 * 1024 * 1024).max(1)
+  }
+
+  pub(crate) fn max_files(&self) -> u32 {
+    self.max_files.unwrap_or(5)
+  }
+
+  pub(crate) fn tail_lines(&self) -> usize {
+    self.tail_lines.unwrap_or(200)
+  }
+}
+
+/// Socket of a `unix:` upstream URL; relative paths are resolved against the working directory.
+pub(crate) fn unix_socket_path(url: &str, working_dir: Option<&Path>) -> Option<PathBuf> {
+  let path = Path::new(url.strip_prefix("unix:")?);
+  Some(match working_dir {
+    Some(working_dir) if path.is_relative() => working_dir.join(path),
+    _ => path.to_path_buf(),
+  })
+}
+
+impl ProcessSpec {
+  /// Sockets of `unix:` upstreams the process is expected to listen on.
+  pub(crate) fn socket_paths(&self) -> Vec<PathBuf> {
+    self
+      .upstreams
+      .iter()
+      .filter_map(|u| unix_socket_path(u, Some(&self.working_dir)))
+      .collect()
+  }
+
+  /// Upstream addresses (`host:port`) the process is expected to listen on.
+  pub(crate) fn listen_addrs(&self) -> Vec<String> {
+    self
+      .upstreams
+      .iter()
+      .filter_map(|u| reqwest::Url::parse(u).ok())
+      .filter_map(|u| Some(format!("{}:{}", u.host_str()?, u.port_or_known_default()?)))
+      .collect()
+  }
+}
+
+impl CommonService {
+  /// Path prefix without the trailing slash; empty if the service serves the whole host.
+  pub(crate) fn path_prefix(&self) -> &str {
+    self.path_prefix.as_deref().unwrap_or_default().trim_end_matches('/')
+  }
+
+  /// `env_file` with a relative path resolved against `working_dir`.
+  pub(crate) fn env_file_path(&self) -> Option<PathBuf> {
+    let env_file = self.env_file.as_ref()?;
+    Some(match &self.working_dir {
+      Some(working_dir) => working_dir.join(env_file),
+      None => env_file.clone(),
+    })
+  }
+
+  pub(crate) fn process_spec(&self) -> Option<ProcessSpec> {
+    Some(ProcessSpec {
+      startup_cmd: self.startup_cmd.clone()?,
+      startup_args: self.startup_args.clone().unwrap_or_default(),
+      working_dir: self.working_dir.clone()?,
+      env: self.env.clone().unwrap_or_default(),
+      env_file: self.env_file_path(),
+      user: self.user.clone(),
+      group: self.group.clone(),
+      umask: self.umask.clone(),
+      upstreams: self.to.iter().map(|u| u.url().to_owned()).collect(),
+      readiness: self.readiness.clone(),
+      wait_after: std::time::Duration::from_secs(self.wait_after.unwrap_or_default()),
+      ready_timeout: std::time::Duration::from_secs(self.ready_timeout.unwrap_or(30)),
+      restart: self.restart.clone().unwrap_or_default(),
+      stop_timeout: std::time::Duration::from_secs(self.stop_timeout.unwrap_or(10)),
+      logs: self.logs.clone().unwrap_or_default(),
+    })
   }
 }
 
 impl LbrpConfig {
-  pub(crate) fn validate(&self) -> MResult<()> {
-    if let Some(invalid) = self
+  pub(crate) fn service_names(&self) -> Vec<String> {
+    self
       .services
       .iter()
       .filter_map(|s| match s {
-        Service::CommonService(service) => Some(service),
+        Service::CommonService(service) => Some(service.service_name.clone()),
+        Service::TcpStream(service) | Service::UdpStream(service) => Some(service.service_name.clone()),
         _ => None,
       })
-      .find(|s| !s.to.starts_with("http://") && !s.to.starts_with("https://"))
-    {
-      ServerError::from_public(format!(
-        "You aren't specified what schema (`http` or `https`) LBRP must use with `{}`",
-        invalid.to
-      ))
-      .with_405()
-      .bail()?;
-    }
-    Ok(())
+      .collect()
+  }
+
+  pub(crate) fn common_services(&self) -> impl Iterator<Item = &CommonService> {
+    self.services.iter().filter_map(|s| match s {
+      Service::CommonService(service) => Some(service),
+      _ => None,
+    })
   }
+
+  /// Hosts served by this node.
+  pub(crate) fn hosts(&self) -> Vec<String> {
+    self
+      .services
+      .iter()
+      .filter_map(|s| match s {
+        Service::CommonService(service) => Some(service.from.clone()),
+        _ => None,
+      })
+      .fold(vec![], |mut hosts, host| {
+        if !hosts.contains(&host) {
+          hosts.push(host);
+        }
+        hosts
+      })
+  }
+}
+
+/// Reads, parses and validates the config file.
+pub(crate) fn load_config<P: AsRef<std::path::Path>>(config_path: P) -> MResult<LbrpConfig> {
+  let config_path = config_path.as_ref();
+  let data = std::fs::read(config_path).map_err(|e| {
+    ServerError::from_private(e)
+      .with_public(format!("Can't open `{}`!", config_path.display()))
+      .with_500()
+  })?;
+  let config = serde_json::from_slice::<LbrpConfig>(&data).map_err(|e| {
+    tracing::error!(
+      config_file = %config_path.display(),
+      line = e.line(),
+      column = e.column(),
+      "Can't parse the config: {}",
+      e
+    );
+    ServerError::from_public(format!(
+      "Invalid config at line {}, column {}: {}",
+      e.line(),
+      e.column(),
+      e
+    ))
+    .with_400()
+  })?;
+  config.validate()?;
+  Ok(config)
 }
 
+/// Editors may write a file in several steps, so reload waits until modify events stop for this long.
+const RELOAD_DEBOUNCE: std::time::Duration = std::time::Duration::from_millis(500);
+
+/// Watches the directory of the config, because editors often save files by renaming a new one over them.
 pub(crate) async fn config_watcher<P: AsRef<std::path::Path>>(
   config_path: P,
   reload_tx: tokio::sync::broadcast::Sender<()>,
 ) -> notify::Result<()> {
   use notify::{Config, RecommendedWatcher, RecursiveMode, Watcher};
 
+  let config_path = config_path.as_ref();
+  let Some(file_name) = config_path.file_name().map(|name| name.to_owned()) else {
+    return Err(notify::Error::path_not_found().add_path(config_path.to_path_buf()));
+  };
+  let dir = match config_path.parent() {
+    Some(dir) if !dir.as_os_str().is_empty() => dir,
+    _ => std::path::Path::new("."),
+  };
+  let changes_config = move |event: &notify::Event| {
+    (event.kind.is_modify() || event.kind.is_create()) && event.paths.iter().any(|p| p.file_name() == Some(&file_name))
+  };
+
   let (tx, mut rx) = tokio::sync::mpsc::channel(1);
   let mut watcher = RecommendedWatcher::new(move |res| tx.blocking_send(res).unwrap(), Config::default())?;
-  watcher.watch(config_path.as_ref(), RecursiveMode::NonRecursive)?;
+  watcher.watch(dir, RecursiveMode::NonRecursive)?;
 
   while let Some(res) = rx.recv().await {
     match res {
-      Ok(event) if event.kind.is_modify() => {
+      Ok(event) if changes_config(&event) => {
+        let mut deadline = tokio::time::Instant::now() + RELOAD_DEBOUNCE;
+        loop {
+          match tokio::time::timeout_at(deadline, rx.recv()).await {
+            Ok(Some(Ok(event))) if changes_config(&event) => deadline = tokio::time::Instant::now() + RELOAD_DEBOUNCE,
+            Ok(Some(_)) => {}
+            Ok(None) => return Ok(()),
+            Err(_) => break,
+          }
+        }
         let _ = reload_tx.send(());
       }
       Err(e) => println!("Watch error: {e:?}"),
@@ -0,0 +1,1070 @@
+use impulse_server_kit::prelude::*;
+use impulse_server_kit::salvo::hyper::Method;
+use impulse_server_kit::salvo::hyper::header::{HeaderName, HeaderValue};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use crate::config::{
+  CommonService, HeaderOps, LbrpConfig, LbrpMode, LbrpPCMode, LbrpYBOBMode, ReadinessProbe, Rule, RuleAction, Service,
+  StreamService, UpstreamList,
+};
+use crate::header_handling::{HEADER_VARIABLES, TrustedProxies};
+use crate::process_management::{lookup_group, lookup_user, parse_umask};
+use crate::router::{HostPattern, fill_placeholders, placeholders};
+
+/// Problem found in the config.
+#[derive(Debug, Clone)]
+pub(crate) enum ConfigIssue {
+  InvalidServiceName(String),
+  DuplicateRoute {
+    route: String,
+    services: Vec<String>,
+  },
+  InvalidHostPattern {
+    service: String,
+    reason: String,
+  },
+  UnknownPlaceholder {
+    service: String,
+    url: String,
+    name: String,
+  },
+  PlaceholderHealthCheck {
+    service: String,
+  },
+  ZeroHealthCheckPeriod {
+    service: String,
+    setting: &'static str,
+  },
+  InvalidHeaderOp {
+    service: String,
+    reason: String,
+  },
+  InvalidRule {
+    service: String,
+    idx: usize,
+    reason: String,
+  },
+  InvalidPathPrefix {
+    service: String,
+    prefix: String,
+  },
+  InvalidPathRegex {
+    service: String,
+    reason: String,
+  },
+  MultipleErrorHandlers(usize),
+  MultipleStatics(usize),
+  NoUpstreams {
+    service: String,
+  },
+  InvalidUpstream {
+    service: String,
+    url: String,
+    reason: String,
+  },
+  ZeroWeight {
+    service: String,
+    url: String,
+  },
+  PathNotFound {
+    field: &'static str,
+    path: PathBuf,
+  },
+  UnknownDependency {
+    service: String,
+    dependency: String,
+  },
+  DependencyCycle(Vec<String>),
+  InvalidReadinessPattern {
+    service: String,
+    reason: String,
+  },
+  UnknownUser {
+    service: String,
+    user: String,
+  },
+  UnknownGroup {
+    service: String,
+    group: String,
+  },
+  UserWithoutGroup {
+    service: String,
+    user: String,
+  },
+  InvalidUmask {
+    service: String,
+    umask: String,
+  },
+  InvalidHeaderName {
+    service: String,
+    header: String,
+  },
+  InvalidCorsOrigin {
+    service: String,
+    origin: String,
+  },
+  InvalidRetryStatus {
+    service: String,
+    status: u16,
+  },
+  Http2WithProxyProtocol {
+    service: String,
+  },
+  InvalidListenAddr {
+    service: String,
+    addr: String,
+  },
+  DuplicateListen {
+    listen: String,
+    services: Vec<String>,
+  },
+  TlsOverUdp {
+    service: String,
+  },
+  NoCertificates {
+    service: String,
+  },
+  InvalidSni {
+    service: String,
+    sni: String,
+    reason: String,
+  },
+  InvalidTrustedProxy(String),
+  InvalidProxyProtocolAddr {
+    setting: &'static str,
+    addr: String,
+  },
+  MissingClusterSettings {
+    role: &'static str,
+    settings: &'static str,
+  },
+  UnmanageableNode {
+    node_id: String,
+  },
+}
+
+impl std::fmt::Display for ConfigIssue {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Self::InvalidServiceName(service) => {
+        write!(
+          f,
+          "service name `{service}` names its log file and can't contain `/`, `\\` or `..`"
+        )
+      }
+      Self::DuplicateRoute { route, services } => {
+        write!(
+          f,
+          "route `{route}` is served by several services: {}",
+          services.join(", ")
+        )
+      }
+      Self::InvalidHostPattern { service, reason } => write!(f, "`from` of service `{service}` is invalid: {reason}"),
+      Self::UnknownPlaceholder { service, url, name } => {
+        write!(
+          f,
+          "upstream `{url}` of service `{service}` uses `{{{name}}}` which is not captured by `from`"
+        )
+      }
+      Self::PlaceholderHealthCheck { service } => {
+        write!(
+          f,
+          "service `{service}` can't have `health_check` with placeholders in upstream URLs"
+        )
+      }
+      Self::ZeroHealthCheckPeriod { service, setting } => {
+        write!(f, "`health_check.{setting}` of service `{service}` must be positive")
+      }
+      Self::InvalidHeaderOp { service, reason } => {
+        write!(f, "header operation of service `{service}` is invalid: {reason}")
+      }
+      Self::InvalidRule { service, idx, reason } => {
+        write!(f, "rule #{idx} of service `{service}` is invalid: {reason}")
+      }
+      Self::InvalidPathPrefix { service, prefix } => {
+        write!(f, "path prefix `{prefix}` of service `{service}` must start with `/`")
+      }
+      Self::InvalidPathRegex { service, reason } => {
+        write!(f, "path regex of service `{service}` is invalid: {reason}")
+      }
+      Self::MultipleErrorHandlers(count) => {
+        write!(f, "there are {count} `error_handler` entries, at most one is allowed")
+      }
+      Self::MultipleStatics(count) => write!(f, "there are {count} `common_static` entries, at most one is allowed"),
+      Self::NoUpstreams { service } => write!(f, "service `{service}` must have at least one upstream"),
+      Self::InvalidUpstream { service, url, reason } => {
+        write!(f, "upstream `{url}` of service `{service}` is invalid: {reason}")
+      }
+      Self::ZeroWeight { service, url } => {
+        write!(f, "upstream `{url}` of service `{service}` must have a positive weight")
+      }
+      Self::PathNotFound { field, path } => write!(f, "`{field}` path `{}` doesn't exist", path.display()),
+      Self::UnknownDependency { service, dependency } => {
+        write!(
+          f,
+          "service `{service}` depends on `{dependency}` which is not a managed service"
+        )
+      }
+      Self::DependencyCycle(services) => write!(f, "services depend on each other: {}", services.join(" -> ")),
+      Self::InvalidReadinessPattern { service, reason } => {
+        write!(f, "readiness pattern of service `{service}` is invalid: {reason}")
+      }
+      Self::UnknownUser { service, user } => write!(f, "user `{user}` of service `{service}` doesn't exist"),
+      Self::UnknownGroup { service, group } => write!(f, "group `{group}` of service `{service}` doesn't exist"),
+      Self::UserWithoutGroup { service, user } => {
+        write!(
+          f,
+          "user `{user}` of service `{service}` has no primary group, set `group`"
+        )
+      }
+      Self::InvalidUmask { service, umask } => {
+        write!(
+          f,
+          "umask `{umask}` of service `{service}` must be an octal number up to `777`"
+        )
+      }
+      Self::InvalidHeaderName { service, header } => {
+        write!(
+          f,
+          "`provide_ip_as_header` of service `{service}` is not a valid header name: `{header}`"
+        )
+      }
+      Self::InvalidCorsOrigin { service, origin } => write!(
+        f,
+        "CORS origin `{origin}` of service `{service}` must look like `https://example.com[:port]`"
+      ),
+      Self::InvalidRetryStatus { service, status } => {
+        write!(
+          f,
+          "retry status `{status}` of service `{service}` is not a valid HTTP status"
+        )
+      }
+      Self::Http2WithProxyProtocol { service } => write!(
+        f,
+        "service `{service}` sends PROXY protocol header over HTTP/1.1 and can't use `http2_prior_knowledge`"
+      ),
+      Self::InvalidListenAddr { service, addr } => {
+        write!(
+          f,
+          "`listen` of service `{service}` must look like `0.0.0.0:5432`, got `{addr}`"
+        )
+      }
+      Self::DuplicateListen { listen, services } => {
+        write!(f, "`{listen}` is listened by several services: {}", services.join(", "))
+      }
+      Self::TlsOverUdp { service } => write!(f, "UDP service `{service}` can't terminate TLS"),
+      Self::NoCertificates { service } => write!(f, "`tls` of service `{service}` must have at least one certificate"),
+      Self::InvalidSni { service, sni, reason } => {
+        write!(f, "`sni` pattern `{sni}` of service `{service}` is invalid: {reason}")
+      }
+      Self::InvalidTrustedProxy(net) => {
+        write!(
+          f,
+          "trusted proxy `{net}` must be an address or a network like `10.0.0.0/8`"
+        )
+      }
+      Self::InvalidProxyProtocolAddr { setting, addr } => {
+        write!(
+          f,
+          "`proxy_protocol.{setting}` must look like `0.0.0.0:8443`, got `{addr}`"
+        )
+      }
+      Self::MissingClusterSettings { role, settings } => write!(f, "{role} must have {settings} specified"),
+      Self::UnmanageableNode { node_id } => {
+        write!(f, "managed node `{node_id}` must have `cluster.control_addr` specified")
+      }
+    }
+  }
+}
+
+/// Every problem found in the config.
+#[derive(Debug, Clone)]
+pub(crate) struct ConfigErrors(pub(crate) Vec<ConfigIssue>);
+
+impl std::fmt::Display for ConfigErrors {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "config has {} problem(s):", self.0.len())?;
+    for issue in &self.0 {
+      write!(f, "\n- {issue}")?;
+    }
+    Ok(())
+  }
+}
+
+impl std::error::Error for ConfigErrors {}
+
+impl From<ConfigErrors> for ServerError {
+  fn from(errors: ConfigErrors) -> Self {
+    ServerError::from_public(errors.to_string()).with_405()
+  }
+}
+
+/// Checks whether the command could be spawned from `working_dir`.
+fn command_exists(cmd: &Path, working_dir: Option<&Path>) -> bool {
+  if cmd.is_absolute() {
+    return cmd.exists();
+  }
+  if cmd.exists() || working_dir.is_some_and(|dir| dir.join(cmd).exists()) {
+    return true;
+  }
+  cmd.components().count() == 1
+    && std::env::var_os("PATH").is_some_and(|paths| std::env::split_paths(&paths).any(|dir| dir.join(cmd).exists()))
+}
+
+fn is_valid_origin(origin: &str) -> bool {
+  reqwest::Url::parse(origin)
+    .is_ok_and(|url| matches!(url.scheme(), "http" | "https") && url.origin().ascii_serialization() == origin)
+}
+
+fn validate_service(service: &CommonService, check_paths: bool, issues: &mut Vec<ConfigIssue>) {
+  if ["/", "\\", ".."].iter().any(|part| service.service_name.contains(part)) {
+    issues.push(ConfigIssue::InvalidServiceName(service.service_name.clone()));
+  }
+  let capture_names = match HostPattern::parse(&service.from) {
+    Ok(pattern) => pattern.capture_names(),
+    Err(e) => {
+      issues.push(ConfigIssue::InvalidHostPattern {
+        service: service.service_name.clone(),
+        reason: e.to_string(),
+      });
+      vec![]
+    }
+  };
+  if let Some(prefix) = &service.path_prefix
+    && !prefix.starts_with('/')
+  {
+    issues.push(ConfigIssue::InvalidPathPrefix {
+      service: service.service_name.clone(),
+      prefix: prefix.clone(),
+    });
+  }
+  if let Some(regex) = &service.path_regex
+    && let Err(e) = regex::Regex::new(regex)
+  {
+    issues.push(ConfigIssue::InvalidPathRegex {
+      service: service.service_name.clone(),
+      reason: e.to_string(),
+    });
+  }
+
+  if service.to.iter().next().is_none() {
+    issues.push(ConfigIssue::NoUpstreams {
+      service: service.service_name.clone(),
+    });
+  }
+  let mut has_placeholders = false;
+  for upstream in service.to.iter() {
+    let names = placeholders(upstream.url());
+    for name in &names {
+      if !capture_names.iter().any(|c| c == name) {
+        issues.push(ConfigIssue::UnknownPlaceholder {
+          service: service.service_name.clone(),
+          url: upstream.url().to_string(),
+          name: name.to_string(),
+        });
+      }
+    }
+    has_placeholders |= !names.is_empty();
+    let sample = names
+      .iter()
+      .map(|name| (name.to_string(), "x".to_string()))
+      .collect::<Vec<_>>();
+    let reason = match reqwest::Url::parse(&fill_placeholders(upstream.url(), &sample)) {
+      Ok(url) if url.scheme() == "unix" && url.path().is_empty() => Some("there is no socket path".to_string()),
+      Ok(url) if url.scheme() == "unix" && !names.is_empty() => {
+        Some("socket paths can't have placeholders".to_string())
+      }
+      Ok(url) if url.scheme() == "unix" => None,
+      Ok(url) if !matches!(url.scheme(), "http" | "https") => {
+        Some("you aren't specified what schema (`http`, `https` or `unix`) LBRP must use".to_string())
+      }
+      Ok(url) if url.host().is_none() => Some("there is no host".to_string()),
+      Ok(url) if service.send_proxy_protocol.is_some() && url.scheme() != "http" => {
+        Some("PROXY protocol header can be sent to `http` upstreams only".to_string())
+      }
+      Ok(_) => None,
+      Err(e) => Some(e.to_string()),
+    };
+    if let Some(reason) = reason {
+      issues.push(ConfigIssue::InvalidUpstream {
+        service: service.service_name.clone(),
+        url: upstream.url().to_string(),
+        reason,
+      });
+    }
+    if upstream.weight() == 0 {
+      issues.push(ConfigIssue::ZeroWeight {
+        service: service.service_name.clone(),
+        url: upstream.url().to_string(),
+      });
+    }
+  }
+
+  for ops in [&service.request_headers, &service.response_headers]
+    .into_iter()
+    .flatten()
+  {
+    for reason in header_ops_problems(ops) {
+      issues.push(ConfigIssue::InvalidHeaderOp {
+        service: service.service_name.clone(),
+        reason,
+      });
+    }
+  }
+
+  for (idx, rule) in service.rules.iter().flatten().enumerate() {
+    if let Some(reason) = rule_problem(rule) {
+      issues.push(ConfigIssue::InvalidRule {
+        service: service.service_name.clone(),
+        idx: idx + 1,
+        reason,
+      });
+    }
+  }
+
+  if has_placeholders && service.health_check.is_some() {
+    issues.push(ConfigIssue::PlaceholderHealthCheck {
+      service: service.service_name.clone(),
+    });
+  }
+  if let Some(health_check) = &service.health_check {
+    for (setting, value) in [("interval", health_check.interval), ("timeout", health_check.timeout)] {
+      if value == Some(0) {
+        issues.push(ConfigIssue::ZeroHealthCheckPeriod {
+          service: service.service_name.clone(),
+          setting,
+        });
+      }
+    }
+  }
+
+  if check_paths
+    && let Some(working_dir) = &service.working_dir
+    && !working_dir.is_dir()
+  {
+    issues.push(ConfigIssue::PathNotFound {
+      field: "working_dir",
+      path: working_dir.clone(),
+    });
+  }
+  if check_paths
+    && let Some(startup_cmd) = &service.startup_cmd
+    && !command_exists(startup_cmd, service.working_dir.as_deref())
+  {
+    issues.push(ConfigIssue::PathNotFound {
+      field: "startup_cmd",
+      path: startup_cmd.clone(),
+    });
+  }
+
+  if check_paths
+    && let Some(env_file) = service.env_file_path()
+    && !env_file.is_file()
+  {
+    issues.push(ConfigIssue::PathNotFound {
+      field: "env_file",
+      path: env_file,
+    });
+  }
+  if check_paths && let Some(user) = &service.user {
+    match lookup_user(user) {
+      None => issues.push(ConfigIssue::UnknownUser {
+        service: service.service_name.clone(),
+        user: user.clone(),
+      }),
+      Some((_, None)) if service.group.is_none() => issues.push(ConfigIssue::UserWithoutGroup {
+        service: service.service_name.clone(),
+        user: user.clone(),
+      }),
+      Some(_) => {}
+    }
+  }
+  if check_paths
+    && let Some(group) = &service.group
+    && lookup_group(group).is_none()
+  {
+    issues.push(ConfigIssue::UnknownGroup {
+      service: service.service_name.clone(),
+      group: group.clone(),
+    });
+  }
+  if let Some(umask) = &service.umask
+    && parse_umask(umask).is_none()
+  {
+    issues.push(ConfigIssue::InvalidUmask {
+      service: service.service_name.clone(),
+      umask: umask.clone(),
+    });
+  }
+
+  if let Some(ReadinessProbe::LogLine { pattern }) = &service.readiness
+    && let Err(e) = regex::Regex::new(pattern)
+  {
+    issues.push(ConfigIssue::InvalidReadinessPattern {
+      service: service.service_name.clone(),
+      reason: e.to_string(),
+    });
+  }
+
+  if let Some(header) = &service.provide_ip_as_header
+    && HeaderName::from_bytes(header.as_bytes()).is_err()
+  {
+    issues.push(ConfigIssue::InvalidHeaderName {
+      service: service.service_name.clone(),
+      header: header.clone(),
+    });
+  }
+  for origin in service.cors_domains.iter().flatten() {
+    if !is_valid_origin(origin) {
+      issues.push(ConfigIssue::InvalidCorsOrigin {
+        service: service.service_name.clone(),
+        origin: origin.clone(),
+      });
+    }
+  }
+  if service.send_proxy_protocol.is_some()
+    && service
+      .connection_pool
+      .as_ref()
+      .is_some_and(|pool| pool.http2_prior_knowledge.is_some_and(|v| v))
+  {
+    issues.push(ConfigIssue::Http2WithProxyProtocol {
+      service: service.service_name.clone(),
+    });
+  }
+  for status in service
+    .retry
+    .iter()
+    .flat_map(|retry| retry.on_statuses.iter().flatten())
+  {
+    if !(100..600).contains(status) {
+      issues.push(ConfigIssue::InvalidRetryStatus {
+        service: service.service_name.clone(),
+        status: *status,
+      });
+    }
+  }
+}
+
+/// Checks `host:port` upstreams of a stream service; TCP upstreams may also be `unix:` sockets.
+fn validate_stream_upstreams(service: &str, to: &UpstreamList, udp: bool, issues: &mut Vec<ConfigIssue>) {
+  if to.iter().next().is_none() {
+    issues.push(ConfigIssue::NoUpstreams {
+      service: service.to_string(),
+    });
+  }
+  for upstream in to.iter() {
+    let reason = match upstream.url().strip_prefix("unix:") {
+      Some(_) if udp => Some("UDP upstreams can't be unix sockets".to_string()),
+      Some("") => Some("there is no socket path".to_string()),
+      Some(_) => None,
+      None => match upstream.url().rsplit_once(':') {
+        Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => None,
+        _ => Some("it must look like `host:port`".to_string()),
+      },
+    };
+    if let Some(reason) = reason {
+      issues.push(ConfigIssue::InvalidUpstream {
+        service: service.to_string(),
+        url: upstream.url().to_string(),
+        reason,
+      });
+    }
+    if upstream.weight() == 0 {
+      issues.push(ConfigIssue::ZeroWeight {
+        service: service.to_string(),
+        url: upstream.url().to_string(),
+      });
+    }
+  }
+}
+
+fn validate_stream_service(service: &StreamService, udp: bool, check_paths: bool, issues: &mut Vec<ConfigIssue>) {
+  let name = &service.service_name;
+  if service.listen.parse::<std::net::SocketAddr>().is_err() {
+    issues.push(ConfigIssue::InvalidListenAddr {
+      service: name.clone(),
+      addr: service.listen.clone(),
+    });
+  }
+  validate_stream_upstreams(name, &service.to, udp, issues);
+
+  let Some(tls) = &service.tls else {
+    return;
+  };
+  if udp {
+    issues.push(ConfigIssue::TlsOverUdp { service: name.clone() });
+  }
+  if tls.certificates.is_empty() {
+    issues.push(ConfigIssue::NoCertificates { service: name.clone() });
+  }
+  let patterns = tls
+    .certificates
+    .iter()
+    .map(|c| &c.sni)
+    .chain(tls.routes.iter().flatten().map(|r| &r.sni));
+  for sni in patterns {
+    if let Err(e) = HostPattern::parse(sni) {
+      issues.push(ConfigIssue::InvalidSni {
+        service: name.clone(),
+        sni: sni.clone(),
+        reason: e.to_string(),
+      });
+    }
+  }
+  for route in tls.routes.iter().flatten() {
+    validate_stream_upstreams(name, &route.to, udp, issues);
+  }
+  for certificate in tls.certificates.iter().filter(|_| check_paths) {
+    for (field, path) in [("cert", &certificate.cert), ("key", &certificate.key)] {
+      if !path.exists() {
+        issues.push(ConfigIssue::PathNotFound {
+          field,
+          path: path.clone(),
+        });
+      }
+    }
+  }
+}
+
+/// Returns problems of header operations.
+fn header_ops_problems(ops: &HeaderOps) -> Vec<String> {
+  let mut problems = vec![];
+  let names = ops
+    .remove
+    .iter()
+    .flatten()
+    .chain(ops.set.iter().chain(ops.append.iter()).flat_map(|m| m.keys()));
+  for name in names {
+    if HeaderName::from_bytes(name.as_bytes()).is_err() {
+      problems.push(format!("`{name}` is not a valid header name"));
+    }
+  }
+  for value in ops.set.iter().chain(ops.append.iter()).flat_map(|m| m.values()) {
+    let variables = placeholders(value);
+    let sample = variables
+      .iter()
+      .map(|name| (name.to_string(), "x".to_string()))
+      .collect::<Vec<_>>();
+    if let Some(unknown) = variables.iter().find(|v| !HEADER_VARIABLES.contains(v)) {
+      problems.push(format!(
+        "`{{{unknown}}}` is not a variable, use one of {HEADER_VARIABLES:?}"
+      ));
+    } else if HeaderValue::from_str(&fill_placeholders(value, &sample)).is_err() {
+      problems.push(format!("`{value}` is not a valid header value"));
+    }
+  }
+  problems
+}
+
+/// Returns the first problem of the rule.
+fn rule_problem(rule: &Rule) -> Option<String> {
+  let regexes = rule
+    .r#match
+    .path
+    .iter()
+    .chain(rule.r#match.headers.iter().flat_map(|h| h.values()))
+    .chain(rule.r#match.query.iter().flat_map(|q| q.values()));
+  for regex in regexes {
+    if let Err(e) = regex::Regex::new(regex) {
+      return Some(e.to_string());
+    }
+  }
+  if let Some(header) = rule
+    .r#match
+    .headers
+    .iter()
+    .flat_map(|h| h.keys())
+    .find(|h| HeaderName::from_bytes(h.as_bytes()).is_err())
+  {
+    return Some(format!("`{header}` is not a valid header name"));
+  }
+  if let Some(method) = rule
+    .r#match
+    .methods
+    .iter()
+    .flatten()
+    .find(|m| Method::from_bytes(m.as_bytes()).is_err())
+  {
+    return Some(format!("`{method}` is not a valid method"));
+  }
+  match &rule.action {
+    RuleAction::Rewrite { path } if rule.r#match.path.is_none() && !path.starts_with('/') => {
+      Some("rewritten path must start with `/`".to_string())
+    }
+    RuleAction::AddPrefix { prefix } | RuleAction::StripPrefix { prefix } if !prefix.starts_with('/') => {
+      Some("prefix must start with `/`".to_string())
+    }
+    RuleAction::Redirect {
+      status: Some(status), ..
+    } if ![301, 302, 307, 308].contains(status) => {
+      Some(format!("redirect status must be 301, 302, 307 or 308, not {status}"))
+    }
+    RuleAction::Respond { status, .. } if !(100..=599).contains(status) => {
+      Some(format!("`{status}` is not a valid status"))
+    }
+    _ => None,
+  }
+}
+
+/// Reports `depends_on` entries which are not managed services, and dependency cycles.
+fn validate_dependencies(config: &LbrpConfig, issues: &mut Vec<ConfigIssue>) {
+  let managed = config
+    .common_services()
+    .filter(|s| s.process_spec().is_some())
+    .map(|s| (s.service_name.as_str(), s.depends_on.as_deref().unwrap_or_default()))
+    .collect::<HashMap<_, _>>();
+
+  for service in config.common_services() {
+    for dependency in service.depends_on.iter().flatten() {
+      if !managed.contains_key(dependency.as_str()) {
+        issues.push(ConfigIssue::UnknownDependency {
+          service: service.service_name.clone(),
+          dependency: dependency.clone(),
+        });
+      }
+    }
+  }
+
+  /// Depth-first search keeping the current path to report the cycle.
+  fn visit<'a>(
+    name: &'a str,
+    managed: &HashMap<&'a str, &'a [String]>,
+    path: &mut Vec<&'a str>,
+    done: &mut HashSet<&'a str>,
+    issues: &mut Vec<ConfigIssue>,
+  ) {
+    if done.contains(name) {
+      return;
+    }
+    if let Some(start) = path.iter().position(|n| *n == name) {
+      let mut cycle = path[start..].iter().map(|n| n.to_string()).collect::<Vec<_>>();
+      cycle.push(name.to_string());
+      issues.push(ConfigIssue::DependencyCycle(cycle));
+      return;
+    }
+    path.push(name);
+    for dependency in managed.get(name).copied().unwrap_or_default() {
+      if let Some((dependency, _)) = managed.get_key_value(dependency.as_str()) {
+        visit(*dependency, managed, path, done, issues);
+      }
+    }
+    path.pop();
+    done.insert(name);
+  }
+
+  let mut names = managed.keys().copied().collect::<Vec<_>>();
+  names.sort();
+  let mut done = HashSet::new();
+  for name in names {
+    visit(name, &managed, &mut vec![], &mut done, issues);
+  }
+}
+
+fn validate_cluster(config: &LbrpConfig, issues: &mut Vec<ConfigIssue>) {
+  let cluster = config.cluster.clone().unwrap_or_default();
+  let missing = match &config.lbrp_mode {
+    LbrpMode::PC(LbrpPCMode::Parent) if cluster.control_addr.is_none() => {
+      Some(("Parent node", "`cluster.control_addr`"))
+    }
+    LbrpMode::PC(LbrpPCMode::Child) if cluster.parent.is_none() || cluster.public_url.is_none() => {
+      Some(("Child node", "`cluster.parent` and `cluster.public_url`"))
+    }
+    LbrpMode::Ybob(LbrpYBOBMode::OlderBrother) if cluster.control_addr.is_none() => {
+      Some(("Older brother", "`cluster.control_addr`"))
+    }
+    LbrpMode::Ybob(LbrpYBOBMode::YoungerBrother) if cluster.older_brother.is_none() || cluster.public_url.is_none() => {
+      Some(("Younger brother", "`cluster.older_brother` and `cluster.public_url`"))
+    }
+    LbrpMode::Supervisor if cluster.secret.is_none() || cluster.nodes.as_ref().is_none_or(|nodes| nodes.is_empty()) => {
+      Some(("Supervisor", "`cluster.secret` and `cluster.nodes`"))
+    }
+    _ => None,
+  };
+  if let Some((role, settings)) = missing {
+    issues.push(ConfigIssue::MissingClusterSettings { role, settings });
+  }
+
+  if let LbrpMode::Supervisor = &config.lbrp_mode {
+    for node in cluster.nodes.iter().flatten() {
+      if node.cluster.control_addr.is_none() {
+        issues.push(ConfigIssue::UnmanageableNode {
+          node_id: node.node_id.clone(),
+        });
+      }
+    }
+  }
+}
+
+impl LbrpConfig {
+  /// Checks the whole config and reports every problem at once.
+  pub(crate) fn validate(&self) -> Result<(), ConfigErrors> {
+    let mut issues = vec![];
+    // The supervisor doesn't run services, so their paths and users exist only on managed nodes.
+    let check_paths = !matches!(self.lbrp_mode, LbrpMode::Supervisor);
+
+    let error_handlers = self
+      .services
+      .iter()
+      .filter(|s| matches!(s, Service::ErrorHandler(_)))
+      .count();
+    if error_handlers > 1 {
+      issues.push(ConfigIssue::MultipleErrorHandlers(error_handlers));
+    }
+    let statics = self
+      .services
+      .iter()
+      .filter(|s| matches!(s, Service::CommonStatic(_)))
+      .count();
+    if statics > 1 {
+      issues.push(ConfigIssue::MultipleStatics(statics));
+    }
+
+    for service in &self.services {
+      match service {
+        Service::ErrorHandler(err_handler) if check_paths && !err_handler.dist_dir.is_dir() => {
+          issues.push(ConfigIssue::PathNotFound {
+            field: "dist_dir",
+            path: err_handler.dist_dir.clone(),
+          });
+        }
+        Service::CommonStatic(r#static) if check_paths && !r#static.path.exists() => {
+          issues.push(ConfigIssue::PathNotFound {
+            field: "path",
+            path: r#static.path.clone(),
+          });
+        }
+        _ => {}
+      }
+    }
+
+    let mut routes = HashMap::<String, Vec<String>>::new();
+    for service in self.common_services() {
+      let mut route = format!("{}{}", service.from, service.path_prefix());
+      if let Some(regex) = &service.path_regex {
+        route = format!("{route} ~ {regex}");
+      }
+      routes.entry(route).or_default().push(service.service_name.clone());
+      validate_service(service, check_paths, &mut issues);
+    }
+    let mut duplicates = routes
+      .into_iter()
+      .filter(|(_, services)| services.len() > 1)
+      .collect::<Vec<_>>();
+    duplicates.sort();
+    for (route, services) in duplicates {
+      issues.push(ConfigIssue::DuplicateRoute { route, services });
+    }
+
+    let mut listens = HashMap::<String, Vec<String>>::new();
+    for service in &self.services {
+      let (service, udp) = match service {
+        Service::TcpStream(service) => (service, false),
+        Service::UdpStream(service) => (service, true),
+        _ => continue,
+      };
+      let protocol = if udp { "udp" } else { "tcp" };
+      listens
+        .entry(format!("{protocol}://{}", service.listen))
+        .or_default()
+        .push(service.service_name.clone());
+      validate_stream_service(service, udp, check_paths, &mut issues);
+    }
+    let mut duplicates = listens
+      .into_iter()
+      .filter(|(_, services)| services.len() > 1)
+      .collect::<Vec<_>>();
+    duplicates.sort();
+    for (listen, services) in duplicates {
+      issues.push(ConfigIssue::DuplicateListen { listen, services });
+    }
+
+    for net in self.trusted_proxies.iter().flatten() {
+      if TrustedProxies::parse_net(net).is_none() {
+        issues.push(ConfigIssue::InvalidTrustedProxy(net.clone()));
+      }
+    }
+    if let Some(listener) = &self.proxy_protocol {
+      for (setting, addr) in [("listen", &listener.listen), ("forward_to", &listener.forward_to)] {
+        if addr.parse::<std::net::SocketAddr>().is_err() {
+          issues.push(ConfigIssue::InvalidProxyProtocolAddr {
+            setting,
+            addr: addr.clone(),
+          });
+        }
+      }
+      for net in &listener.trusted {
+        if TrustedProxies::parse_net(net).is_none() {
+          issues.push(ConfigIssue::InvalidTrustedProxy(net.clone()));
+        }
+      }
+    }
+
+    validate_dependencies(self, &mut issues);
+    validate_cluster(self, &mut issues);
+
+    if issues.is_empty() {
+      Ok(())
+    } else {
+      Err(ConfigErrors(issues))
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+
+  fn issues(services: serde_json::Value) -> Vec<ConfigIssue> {
+    let config = LbrpConfig {
+      services: serde_json::from_value(services).unwrap(),
+      ..Default::default()
+    };
+    config.validate().err().map(|errors| errors.0).unwrap_or_default()
+  }
+
+  fn service(name: &str, from: &str, to: &str) -> serde_json::Value {
+    json!({ "type": "common_service", "service_name": name, "from": from, "to": to })
+  }
+
+  #[test]
+  fn accepts_valid_config() {
+    let issues = issues(json!([
+      service("api", "api.example.com", "http://127.0.0.1:8020"),
+      service("apps", "*.apps.example.com", "http://{1}.internal:8080"),
+    ]));
+    assert!(issues.is_empty(), "{issues:?}");
+  }
+
+  #[test]
+  fn reports_every_issue_at_once() {
+    let issues = issues(json!([
+      service("first", "example.com", "http://127.0.0.1:8020"),
+      service("second", "example.com", "127.0.0.1:8021"),
+      service("apps", "*.example.org", "http://{2}.internal:8080"),
+    ]));
+    assert_eq!(issues.len(), 3, "{issues:?}");
+    assert!(issues.iter().any(|issue| matches!(
+      issue,
+      ConfigIssue::DuplicateRoute { route, services } if route == "example.com" && services == &["first", "second"]
+    )));
+    assert!(issues.iter().any(|issue| matches!(
+      issue,
+      ConfigIssue::InvalidUpstream { service, .. } if service == "second"
+    )));
+    assert!(issues.iter().any(|issue| matches!(
+      issue,
+      ConfigIssue::UnknownPlaceholder { service, name, .. } if service == "apps" && name == "2"
+    )));
+  }
+
+  #[test]
+  fn different_paths_of_a_host_are_not_duplicates() {
+    let mut v1 = service("v1", "example.com", "http://127.0.0.1:8020");
+    v1["path_prefix"] = json!("/v1");
+    let issues = issues(json!([v1, service("root", "example.com", "http://127.0.0.1:8021")]));
+    assert!(issues.is_empty(), "{issues:?}");
+  }
+
+  #[test]
+  fn reports_invalid_paths_and_hosts() {
+    let mut api = service("api", "~(unclosed", "http://127.0.0.1:8020");
+    api["path_prefix"] = json!("v1");
+    api["path_regex"] = json!("[");
+    let issues = issues(json!([api]));
+    assert!(issues.iter().any(|issue| matches!(issue, ConfigIssue::InvalidHostPattern { .. })));
+    assert!(issues.iter().any(|issue| matches!(
+      issue,
+      ConfigIssue::InvalidPathPrefix { prefix, .. } if prefix == "v1"
+    )));
+    assert!(issues.iter().any(|issue| matches!(issue, ConfigIssue::InvalidPathRegex { .. })));
+  }
+
+  #[test]
+  fn reports_service_names_escaping_log_dir() {
+    let issues = issues(json!([
+      service("../etc/cron.d/job", "a.example.com", "http://127.0.0.1:8020"),
+      service("c:\\job", "b.example.com", "http://127.0.0.1:8021"),
+      service("api.v2", "c.example.com", "http://127.0.0.1:8022"),
+    ]));
+    assert_eq!(issues.len(), 2, "{issues:?}");
+    assert!(issues.iter().all(|issue| matches!(issue, ConfigIssue::InvalidServiceName(_))));
+  }
+
+  #[test]
+  fn requires_group_for_user_ids_without_passwd_entry() {
+    let mut api = service("api", "api.example.com", "http://127.0.0.1:8020");
+    api["user"] = json!("4000000000");
+    let without_group = issues(json!([api.clone()]));
+    assert!(matches!(
+      without_group.as_slice(),
+      [ConfigIssue::UserWithoutGroup { service, user }] if service == "api" && user == "4000000000"
+    ));
+
+    api["group"] = json!("4000000000");
+    let with_group = issues(json!([api]));
+    assert!(with_group.is_empty(), "{with_group:?}");
+  }
+
+  #[test]
+  fn reports_zero_health_check_periods() {
+    let mut api = service("api", "api.example.com", "http://127.0.0.1:8020");
+    api["health_check"] = json!({ "path": "/health", "interval": 0, "timeout": 0 });
+    let issues = issues(json!([api]));
+    assert_eq!(issues.len(), 2, "{issues:?}");
+    for expected in ["interval", "timeout"] {
+      assert!(issues.iter().any(|issue| matches!(
+        issue,
+        ConfigIssue::ZeroHealthCheckPeriod { service, setting } if service == "api" && *setting == expected
+      )));
+    }
+  }
+
+  #[test]
+  fn reports_unix_upstreams_with_placeholders() {
+    let issues = issues(json!([service("apps", "*.example.com", "unix:/run/{1}.sock")]));
+    assert!(issues.iter().any(|issue| matches!(
+      issue,
+      ConfigIssue::InvalidUpstream { reason, .. } if reason == "socket paths can't have placeholders"
+    )));
+  }
+
+  #[test]
+  fn reports_unknown_dependencies_and_cycles() {
+    let managed = |name: &str, depends_on: &[&str]| {
+      let mut service = service(name, &format!("{name}.example.com"), "http://127.0.0.1:8020");
+      service["startup_cmd"] = json!("/bin/sh");
+      service["working_dir"] = json!("/");
+      service["depends_on"] = json!(depends_on);
+      service
+    };
+    let issues = issues(json!([
+      managed("a", &["b"]),
+      managed("b", &["a"]),
+      managed("c", &["missing"]),
+    ]));
+    assert!(issues.iter().any(|issue| matches!(
+      issue,
+      ConfigIssue::DependencyCycle(cycle) if cycle == &["a", "b", "a"]
+    )));
+    assert!(issues.iter().any(|issue| matches!(
+      issue,
+      ConfigIssue::UnknownDependency { service, dependency } if service == "c" && dependency == "missing"
+    )));
+  }
+
+  #[test]
+  fn reports_missing_cluster_settings() {
+    let config = LbrpConfig {
+      lbrp_mode: LbrpMode::PC(LbrpPCMode::Parent),
+      ..Default::default()
+    };
+    let issues = config.validate().unwrap_err().0;
+    assert!(matches!(
+      issues.as_slice(),
+      [ConfigIssue::MissingClusterSettings { role: "Parent node", .. }]
+    ));
+  }
+}
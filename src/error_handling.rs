@@ -1,7 +1,119 @@
 use impulse_server_kit::prelude::*;
 use salvo::{FlowCtrl, Response, http::ResBody};
+use std::cell::Cell;
 
 use crate::config::ErrorHandler;
+use crate::header_handling::{REQUEST_ID_HEADER, request_id};
+
+/// Response header naming a failure of `lbrp` itself, so it can be told apart from errors of upstreams.
+///
+/// It is set only by [`GatewayErrors`]; upstream responses are stripped of it.
+pub(crate) const GATEWAY_ERROR_HEADER: &str = "x-lbrp-error";
+/// Depot key of the [`GatewayError`] the request has failed with.
+pub(crate) const GATEWAY_ERROR_KEY: &str = "lbrp-gateway-error";
+
+tokio::task_local! {
+  /// Failure reported by the proxy client, which has no access to the depot.
+  static PROXY_FAILURE: Cell<Option<GatewayError>>;
+}
+
+/// Marks the request handled by the current task as failed by `lbrp` itself.
+pub(crate) fn report_gateway_error(error: GatewayError) {
+  let _ = PROXY_FAILURE.try_with(|failure| failure.set(Some(error)));
+}
+
+/// Failures of proxying a request to an upstream.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum GatewayError {
+  /// Connection refused, DNS resolution or protocol errors.
+  Unreachable,
+  /// The upstream response can't be proxied, e.g. the connection upgrade is refused.
+  BadResponse,
+  Timeout,
+  /// Every upstream is unhealthy or ejected by the circuit breaker.
+  NoUpstream,
+  BodyTooLarge,
+}
+
+impl GatewayError {
+  pub(crate) fn status(self) -> StatusCode {
+    match self {
+      Self::Unreachable | Self::BadResponse => StatusCode::BAD_GATEWAY,
+      Self::Timeout => StatusCode::GATEWAY_TIMEOUT,
+      Self::NoUpstream => StatusCode::SERVICE_UNAVAILABLE,
+      Self::BodyTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+    }
+  }
+
+  pub(crate) fn code(self) -> &'static str {
+    match self {
+      Self::Unreachable => "upstream_unreachable",
+      Self::BadResponse => "bad_upstream_response",
+      Self::Timeout => "upstream_timeout",
+      Self::NoUpstream => "no_healthy_upstream",
+      Self::BodyTooLarge => "body_too_large",
+    }
+  }
+
+  pub(crate) fn message(self) -> &'static str {
+    match self {
+      Self::Unreachable => "Upstream is unreachable!",
+      Self::BadResponse => "Upstream response can't be proxied!",
+      Self::Timeout => "Upstream hasn't responded in time!",
+      Self::NoUpstream => "Service is temporarily unavailable!",
+      Self::BodyTooLarge => "Request body is too large!",
+    }
+  }
+}
+
+/// Renders gateway failures as JSON bodies with the request id.
+pub(crate) struct GatewayErrors;
+
+#[impulse_server_kit::salvo::async_trait]
+impl impulse_server_kit::salvo::Handler for GatewayErrors {
+  #[tracing::instrument(
+    skip_all,
+    name = "gateway-errors",
+    level = "debug",
+    fields(
+      http.uri = req.uri().path(),
+      http.method = req.method().as_str()
+    )
+  )]
+  async fn handle(&self, req: &mut Request, depot: &mut Depot, res: &mut Response, ctrl: &mut salvo::FlowCtrl) {
+    let failure = PROXY_FAILURE
+      .scope(Cell::new(None), async {
+        ctrl.call_next(req, depot, res).await;
+        PROXY_FAILURE.with(Cell::get)
+      })
+      .await;
+    if let Some(error) = failure {
+      depot.insert(GATEWAY_ERROR_KEY, error);
+    }
+
+    let Ok(error) = depot.get::<GatewayError>(GATEWAY_ERROR_KEY).copied() else {
+      return;
+    };
+    let request_id = request_id(req, depot);
+    if let Ok(value) = salvo::http::HeaderValue::from_str(&request_id) {
+      res.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+    res.headers_mut().insert(
+      GATEWAY_ERROR_HEADER,
+      salvo::http::HeaderValue::from_static(error.code()),
+    );
+    res.headers_mut().remove(salvo::http::header::CONTENT_LENGTH);
+    res.headers_mut().remove(salvo::http::header::CONTENT_TYPE);
+    *res.body_mut() = ResBody::None;
+    res.status_code(error.status());
+    res.render(salvo::writing::Json(serde_json::json!({
+      "status": error.status().as_u16(),
+      "error": error.code(),
+      "message": error.message(),
+      "request_id": request_id,
+    })));
+  }
+}
 
 pub static ERR_HANDLER: std::sync::LazyLock<std::sync::Arc<tokio::sync::Mutex<Option<ErrorHandler>>>> =
   std::sync::LazyLock::new(|| std::sync::Arc::new(tokio::sync::Mutex::new(None)));
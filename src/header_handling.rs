@@ -0,0 +1,360 @@
+use impulse_server_kit::prelude::*;
+use impulse_server_kit::salvo::hyper;
+use impulse_server_kit::salvo::hyper::header::{FORWARDED, HOST, HeaderName, HeaderValue};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+use crate::cluster::request_host;
+use crate::config::HeaderOps;
+
+/// Header with the request id; it is generated if the client hasn't sent one.
+pub(crate) const REQUEST_ID_HEADER: &str = "x-request-id";
+/// Variables available in header values.
+pub(crate) const HEADER_VARIABLES: [&str; 4] = ["client_ip", "host", "request_id", "scheme"];
+
+const REQUEST_ID_KEY: &str = "lbrp-request-id";
+
+/// Returns the id of the request, the same for every call.
+pub(crate) fn request_id(req: &Request, depot: &mut Depot) -> String {
+  if let Ok(id) = depot.get::<String>(REQUEST_ID_KEY) {
+    return id.clone();
+  }
+  let id = req
+    .headers()
+    .get(REQUEST_ID_HEADER)
+    .and_then(|v| v.to_str().ok())
+    .map(|v| v.to_owned())
+    .unwrap_or_else(|| format!("{:032x}", rand::random::<u128>()));
+  depot.insert(REQUEST_ID_KEY, id.clone());
+  id
+}
+
+/// Client address; for relayed PROXY protocol connections it is restored by [`RestoreClientAddr`].
+///
+/// [`RestoreClientAddr`]: crate::proxy_protocol::RestoreClientAddr
+pub(crate) fn client_addr(req: &Request) -> Option<SocketAddr> {
+  req.remote_addr().clone().into_std()
+}
+
+/// Client IP without the port.
+pub(crate) fn client_ip(req: &Request) -> Option<IpAddr> {
+  client_addr(req).map(|addr| addr.ip())
+}
+
+/// Addresses of proxies whose forwarding headers are kept.
+#[derive(Default, Debug)]
+pub(crate) struct TrustedProxies(Vec<(IpAddr, u8)>);
+
+impl TrustedProxies {
+  /// Parses a network like `10.0.0.0/8` or a single address.
+  pub(crate) fn parse_net(net: &str) -> Option<(IpAddr, u8)> {
+    let (addr, len) = match net.split_once('/') {
+      Some((addr, len)) => (addr, Some(len.parse::<u8>().ok()?)),
+      None => (net, None),
+    };
+    let addr = addr.parse::<IpAddr>().ok()?.to_canonical();
+    let max_len = if addr.is_ipv4() { 32 } else { 128 };
+    let len = len.unwrap_or(max_len);
+    (len <= max_len).then_some((addr, len))
+  }
+
+  /// Networks are checked on config load.
+  pub(crate) fn new(nets: &[String]) -> Self {
+    Self(nets.iter().filter_map(|net| Self::parse_net(net)).collect())
+  }
+
+  pub(crate) fn contains(&self, ip: IpAddr) -> bool {
+    let same_prefix = |net: u128, ip: u128, len: u8, bits: u8| len == 0 || net >> (bits - len) == ip >> (bits - len);
+    self.0.iter().any(|(net, len)| match (net, ip.to_canonical()) {
+      (IpAddr::V4(net), IpAddr::V4(ip)) => same_prefix(u32::from(*net).into(), u32::from(ip).into(), *len, 32),
+      (IpAddr::V6(net), IpAddr::V6(ip)) => same_prefix(u128::from(*net), u128::from(ip), *len, 128),
+      _ => false,
+    })
+  }
+}
+
+fn render(template: &str, req: &Request, depot: &mut Depot) -> String {
+  let mut value = template.to_string();
+  if value.contains("{client_ip}") {
+    let ip = client_ip(req).map(|ip| ip.to_string()).unwrap_or_default();
+    value = value.replace("{client_ip}", &ip);
+  }
+  if value.contains("{host}") {
+    value = value.replace("{host}", &request_host(req));
+  }
+  if value.contains("{request_id}") {
+    value = value.replace("{request_id}", &request_id(req, depot));
+  }
+  if value.contains("{scheme}") {
+    value = value.replace("{scheme}", &req.scheme().to_string());
+  }
+  value
+}
+
+#[derive(Default)]
+struct CompiledOps {
+  remove: Vec<HeaderName>,
+  set: Vec<(HeaderName, String)>,
+  append: Vec<(HeaderName, String)>,
+}
+
+impl CompiledOps {
+  /// Header names are checked on config load.
+  fn new(ops: &HeaderOps) -> Self {
+    let compile = |map: &Option<std::collections::BTreeMap<String, String>>| {
+      map
+        .iter()
+        .flatten()
+        .filter_map(|(name, value)| Some((HeaderName::from_bytes(name.as_bytes()).ok()?, value.clone())))
+        .collect()
+    };
+    Self {
+      remove: ops
+        .remove
+        .iter()
+        .flatten()
+        .filter_map(|name| HeaderName::from_bytes(name.as_bytes()).ok())
+        .collect(),
+      set: compile(&ops.set),
+      append: compile(&ops.append),
+    }
+  }
+
+  /// Renders values before any header is changed, as they may depend on the request ones.
+  fn render(&self, req: &Request, depot: &mut Depot) -> RenderedOps {
+    let mut render_all = |values: &[(HeaderName, String)]| {
+      values
+        .iter()
+        .filter_map(|(name, template)| {
+          let value = render(template, req, depot);
+          match HeaderValue::from_str(&value) {
+            Ok(value) => Some((name.clone(), value)),
+            Err(_) => {
+              tracing::warn!(header = name.as_str(), value, "Invalid header value, skipping");
+              None
+            }
+          }
+        })
+        .collect::<Vec<_>>()
+    };
+    RenderedOps {
+      set: render_all(&self.set),
+      append: render_all(&self.append),
+    }
+  }
+
+  fn apply(&self, rendered: RenderedOps, headers: &mut hyper::HeaderMap) {
+    for name in &self.remove {
+      headers.remove(name);
+    }
+    for (name, value) in rendered.set {
+      headers.insert(name, value);
+    }
+    for (name, value) in rendered.append {
+      headers.append(name, value);
+    }
+  }
+}
+
+struct RenderedOps {
+  set: Vec<(HeaderName, HeaderValue)>,
+  append: Vec<(HeaderName, HeaderValue)>,
+}
+
+/// Sets, appends and removes headers of requests and responses of a service.
+pub(crate) struct HeadersHandler {
+  request: CompiledOps,
+  response: CompiledOps,
+}
+
+impl HeadersHandler {
+  pub(crate) fn new(request: Option<&HeaderOps>, response: Option<&HeaderOps>) -> Self {
+    Self {
+      request: request.map(CompiledOps::new).unwrap_or_default(),
+      response: response.map(CompiledOps::new).unwrap_or_default(),
+    }
+  }
+}
+
+#[impulse_server_kit::salvo::async_trait]
+impl impulse_server_kit::salvo::Handler for HeadersHandler {
+  #[tracing::instrument(
+    skip_all,
+    name = "headers-handling",
+    level = "debug",
+    fields(
+      http.uri = req.uri().path(),
+      http.method = req.method().as_str()
+    )
+  )]
+  async fn handle(&self, req: &mut Request, depot: &mut Depot, res: &mut Response, ctrl: &mut salvo::FlowCtrl) {
+    let rendered = self.request.render(req, depot);
+    self.request.apply(rendered, req.headers_mut());
+
+    ctrl.call_next(req, depot, res).await;
+
+    let rendered = self.response.render(req, depot);
+    self.response.apply(rendered, res.headers_mut());
+  }
+}
+
+const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+const X_FORWARDED_PROTO: HeaderName = HeaderName::from_static("x-forwarded-proto");
+const X_FORWARDED_HOST: HeaderName = HeaderName::from_static("x-forwarded-host");
+const X_FORWARDED_PORT: HeaderName = HeaderName::from_static("x-forwarded-port");
+const X_REAL_IP: HeaderName = HeaderName::from_static("x-real-ip");
+
+/// Node of the RFC 7239 `Forwarded` header; IPv6 addresses are quoted.
+fn forwarded_node(ip: IpAddr) -> String {
+  match ip {
+    IpAddr::V4(ip) => ip.to_string(),
+    IpAddr::V6(ip) => format!("\"[{ip}]\""),
+  }
+}
+
+/// Provides `Forwarded`, `X-Forwarded-*` and `X-Real-IP` headers.
+///
+/// Headers sent by trusted proxies are extended, headers sent by anyone else are replaced.
+pub(crate) struct ForwardedHeaders {
+  trusted: Arc<TrustedProxies>,
+}
+
+impl ForwardedHeaders {
+  pub(crate) fn new(trusted: Arc<TrustedProxies>) -> Self {
+    Self { trusted }
+  }
+
+  fn provide(&self, req: &mut Request, peer: IpAddr) {
+    let trusted = self.trusted.contains(peer);
+    let joined = |name: &HeaderName| {
+      let values = req
+        .headers()
+        .get_all(name)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .collect::<Vec<_>>();
+      (trusted && !values.is_empty()).then(|| values.join(", "))
+    };
+
+    let mut chain = joined(&X_FORWARDED_FOR)
+      .map(|chain| {
+        chain
+          .split(',')
+          .map(|addr| addr.trim().to_string())
+          .filter(|addr| !addr.is_empty())
+          .collect::<Vec<_>>()
+      })
+      .unwrap_or_default();
+    // The client is the last address which doesn't belong to trusted proxies.
+    let mut real_ip = peer;
+    if trusted {
+      for ip in chain.iter().rev().filter_map(|addr| addr.parse::<IpAddr>().ok()) {
+        real_ip = ip;
+        if !self.trusted.contains(ip) {
+          break;
+        }
+      }
+    }
+    chain.push(peer.to_string());
+
+    let host = req
+      .headers()
+      .get(HOST)
+      .and_then(|v| v.to_str().ok())
+      .map(|v| v.to_owned())
+      .or_else(|| req.uri().authority().map(|a| a.to_string()))
+      .unwrap_or_default();
+    let proto = req.scheme().to_string();
+    let port = req
+      .local_addr()
+      .clone()
+      .into_std()
+      .map(|addr| addr.port().to_string())
+      .unwrap_or_default();
+
+    let element = format!("for={};host=\"{host}\";proto={proto}", forwarded_node(peer));
+    let forwarded = match joined(&FORWARDED) {
+      Some(forwarded) => format!("{forwarded}, {element}"),
+      None => element,
+    };
+    let kept = |name: &HeaderName, value: String| joined(name).unwrap_or(value);
+    let headers = [
+      (FORWARDED, forwarded),
+      (X_FORWARDED_FOR, chain.join(", ")),
+      (X_FORWARDED_PROTO, kept(&X_FORWARDED_PROTO, proto)),
+      (X_FORWARDED_HOST, kept(&X_FORWARDED_HOST, host)),
+      (X_FORWARDED_PORT, kept(&X_FORWARDED_PORT, port)),
+      (X_REAL_IP, real_ip.to_string()),
+    ];
+
+    for (name, value) in headers {
+      match HeaderValue::from_str(&value) {
+        Ok(value) => {
+          req.headers_mut().insert(name, value);
+        }
+        Err(_) => {
+          req.headers_mut().remove(&name);
+        }
+      }
+    }
+  }
+}
+
+#[impulse_server_kit::salvo::async_trait]
+impl impulse_server_kit::salvo::Handler for ForwardedHeaders {
+  #[tracing::instrument(
+    skip_all,
+    name = "forwarded-headers",
+    level = "debug",
+    fields(
+      http.uri = req.uri().path(),
+      http.method = req.method().as_str()
+    )
+  )]
+  async fn handle(&self, req: &mut Request, depot: &mut Depot, res: &mut Response, ctrl: &mut salvo::FlowCtrl) {
+    if let Some(peer) = client_ip(req) {
+      self.provide(req, peer);
+    }
+    ctrl.call_next(req, depot, res).await;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn trusted(nets: &[&str]) -> TrustedProxies {
+    TrustedProxies::new(&nets.iter().map(|net| net.to_string()).collect::<Vec<_>>())
+  }
+
+  #[test]
+  fn contains_networks_and_addresses() {
+    let trusted = trusted(&["10.0.0.0/8", "192.0.2.7", "2001:db8::/32"]);
+    assert!(trusted.contains("10.1.2.3".parse().unwrap()));
+    assert!(!trusted.contains("11.0.0.1".parse().unwrap()));
+    assert!(trusted.contains("192.0.2.7".parse().unwrap()));
+    assert!(!trusted.contains("192.0.2.8".parse().unwrap()));
+    assert!(trusted.contains("2001:db8::1".parse().unwrap()));
+    assert!(!trusted.contains("2001:db9::1".parse().unwrap()));
+  }
+
+  #[test]
+  fn contains_mapped_ipv4() {
+    assert!(trusted(&["10.0.0.0/8"]).contains("::ffff:10.0.0.1".parse().unwrap()));
+    assert!(trusted(&["::ffff:10.0.0.1"]).contains("10.0.0.1".parse().unwrap()));
+  }
+
+  #[test]
+  fn zero_prefix_contains_everything_of_its_family() {
+    let trusted = trusted(&["0.0.0.0/0"]);
+    assert!(trusted.contains("203.0.113.1".parse().unwrap()));
+    assert!(!trusted.contains("2001:db8::1".parse().unwrap()));
+  }
+
+  #[test]
+  fn rejects_invalid_networks() {
+    assert_eq!(TrustedProxies::parse_net("10.0.0.0/33"), None);
+    assert_eq!(TrustedProxies::parse_net("localhost"), None);
+    assert_eq!(TrustedProxies::parse_net("10.0.0.0/x"), None);
+  }
+}
@@ -0,0 +1,114 @@
+use impulse_server_kit::tracing::Instrument;
+use std::path::Path;
+use std::sync::{Arc, Weak};
+
+use crate::config::HealthCheck;
+use crate::proxy_client::{UpstreamPool, socket_get};
+
+/// Consecutive probe results of a single upstream.
+#[derive(Default)]
+struct ProbeCounters {
+  successes: u32,
+  failures: u32,
+}
+
+/// Spawns the background task which probes every upstream of the pool.
+///
+/// The task holds only a weak reference to the pool, so it stops by itself when no router uses the pool anymore.
+pub(crate) fn spawn_health_checker(service_name: String, pool: &Arc<UpstreamPool>, opts: HealthCheck) {
+  let pool = Arc::downgrade(pool);
+  let span = tracing::info_span!("health-check", service = service_name);
+  tokio::spawn(health_checker(pool, opts).instrument(span));
+}
+
+async fn health_checker(pool: Weak<UpstreamPool>, opts: HealthCheck) {
+  let client = match reqwest::Client::builder()
+    .redirect(reqwest::redirect::Policy::none())
+    .timeout(opts.timeout())
+    .build()
+  {
+    Ok(client) => client,
+    Err(e) => {
+      tracing::error!(error = ?e, "Can't build health check client!");
+      return;
+    }
+  };
+
+  let mut counters = vec![];
+  let mut interval = tokio::time::interval(opts.interval());
+  interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+  loop {
+    interval.tick().await;
+
+    let Some(pool) = pool.upgrade() else {
+      tracing::debug!("Upstream pool is dropped, stopping health checks");
+      return;
+    };
+    counters.resize_with(pool.nodes().len(), ProbeCounters::default);
+
+    for (idx, node) in pool.nodes().iter().enumerate() {
+      let span = tracing::debug_span!("probe", upstream = node.url);
+      let passed = match &node.socket {
+        Some(socket) => probe_socket(socket, &opts).instrument(span).await,
+        None => {
+          let url = format!("{}{}", node.url.trim_end_matches('/'), opts.path);
+          probe(&client, &url, &opts).instrument(span).await
+        }
+      };
+
+      let counter = &mut counters[idx];
+      if passed {
+        counter.successes += 1;
+        counter.failures = 0;
+        if counter.successes >= opts.healthy_threshold() && pool.set_healthy(idx, true) {
+          tracing::info!(upstream = node.url, "Upstream is up");
+        }
+      } else {
+        counter.failures += 1;
+        counter.successes = 0;
+        if counter.failures >= opts.unhealthy_threshold() && pool.set_healthy(idx, false) {
+          tracing::warn!(upstream = node.url, "Upstream is down");
+        }
+      }
+    }
+  }
+}
+
+async fn probe(client: &reqwest::Client, url: &str, opts: &HealthCheck) -> bool {
+  match client.get(url).send().await {
+    Ok(response) if opts.is_expected(response.status().as_u16()) => {
+      tracing::trace!(status = response.status().as_u16(), "Probe passed");
+      true
+    }
+    Ok(response) => {
+      tracing::debug!(status = response.status().as_u16(), "Probe failed: unexpected status");
+      false
+    }
+    Err(e) => {
+      tracing::debug!(error = ?e, "Probe failed");
+      false
+    }
+  }
+}
+
+async fn probe_socket(socket: &Path, opts: &HealthCheck) -> bool {
+  match tokio::time::timeout(opts.timeout(), socket_get(socket, &opts.path)).await {
+    Ok(Ok(status)) if opts.is_expected(status.as_u16()) => {
+      tracing::trace!(status = status.as_u16(), "Probe passed");
+      true
+    }
+    Ok(Ok(status)) => {
+      tracing::debug!(status = status.as_u16(), "Probe failed: unexpected status");
+      false
+    }
+    Ok(Err(e)) => {
+      tracing::debug!(error = ?e, "Probe failed");
+      false
+    }
+    Err(_) => {
+      tracing::debug!("Probe failed: timeout");
+      false
+    }
+  }
+}
@@ -0,0 +1,76 @@
+use arc_swap::ArcSwap;
+use impulse_server_kit::prelude::*;
+use impulse_server_kit::salvo::Handler;
+use impulse_server_kit::salvo::routing::PathState;
+use std::sync::Arc;
+
+use crate::error_handling::ErrHandler;
+
+/// Router of the current config with its error catcher.
+pub(crate) struct Routes {
+  pub(crate) router: Router,
+  pub(crate) catcher: Option<ErrHandler>,
+}
+
+/// Service which may be replaced while listeners keep accepting connections.
+///
+/// Requests already in flight finish on the router they started with.
+#[derive(Clone)]
+pub(crate) struct HotSwapService {
+  current: Arc<ArcSwap<Routes>>,
+}
+
+impl HotSwapService {
+  pub(crate) fn new(routes: Routes) -> Self {
+    Self {
+      current: Arc::new(ArcSwap::from_pointee(routes)),
+    }
+  }
+
+  pub(crate) fn swap(&self, routes: Routes) {
+    self.current.store(Arc::new(routes));
+  }
+
+  /// Wraps the switch into a service which could be given to the listeners.
+  pub(crate) fn as_service(&self) -> salvo::Service {
+    salvo::Service::new(Router::with_path("{**rest_path}").goal(self.clone()))
+      .catcher(salvo::catcher::Catcher::default().hoop(HotSwapCatcher(self.clone())))
+  }
+}
+
+/// Routes the request with the current router inside the listeners' flow, so the depot and the connection upgrade
+/// stay with the request.
+#[impulse_server_kit::salvo::async_trait]
+impl impulse_server_kit::salvo::Handler for HotSwapService {
+  async fn handle(&self, req: &mut Request, depot: &mut Depot, res: &mut Response, ctrl: &mut salvo::FlowCtrl) {
+    let routes = self.current.load_full();
+    let mut path_state = PathState::new(req.uri().path());
+    match routes.router.detect(req, &mut path_state).await {
+      Some(matched) => {
+        *req.params_mut() = path_state.params;
+        let mut flow = salvo::FlowCtrl::new([&matched.hoops[..], &[matched.goal]].concat());
+        flow.call_next(req, depot, res).await;
+      }
+      None => {
+        res.status_code(StatusCode::NOT_FOUND);
+      }
+    }
+    ctrl.skip_rest();
+  }
+}
+
+/// Delivers error pages with the catcher of the current config, falling back to the default one.
+struct HotSwapCatcher(HotSwapService);
+
+#[impulse_server_kit::salvo::async_trait]
+impl impulse_server_kit::salvo::Handler for HotSwapCatcher {
+  async fn handle(&self, req: &mut Request, depot: &mut Depot, res: &mut Response, ctrl: &mut salvo::FlowCtrl) {
+    let routes = self.0.current.load_full();
+    let Some(catcher) = &routes.catcher else {
+      ctrl.call_next(req, depot, res).await;
+      return;
+    };
+    catcher.handle(req, depot, res, &mut salvo::FlowCtrl::new(vec![])).await;
+    ctrl.skip_rest();
+  }
+}
@@ -3,11 +3,22 @@
 
 #[cfg(feature = "authnz")]
 mod authnz;
+mod cli;
+mod cluster;
 mod config;
+mod config_validation;
 mod cors_handling;
 mod error_handling;
+mod header_handling;
+mod health_checking;
+mod hot_reload;
+mod process_management;
 mod proxy_client;
+mod proxy_protocol;
+mod request_rules;
 mod router;
+mod service_logs;
+mod stream_proxy;
 
 use authnz::init_authcli;
 use impulse_server_kit::impulse_utils::prelude::*;
@@ -18,16 +29,20 @@ use impulse_server_kit::setup::StartupVariant;
 use impulse_server_kit::startup::{get_root_router_autoinject, start_force_https_redirect, start_with_service};
 use mimalloc::MiMalloc;
 use serde::Deserialize;
-use std::time::Duration;
 use tokio::select;
 use tokio::sync::broadcast;
+use tokio::task::JoinHandle;
 
 #[global_allocator]
 static GLOBAL: MiMalloc = MiMalloc;
 
-use crate::config::{LbrpConfig, Service, config_watcher};
+use crate::cluster::ClusterRuntime;
+use crate::config::{LbrpConfig, LbrpMode, LbrpPCMode, LbrpYBOBMode, Service, config_watcher, load_config};
 use crate::error_handling::ErrHandler;
-use crate::router::get_router_from_config;
+use crate::hot_reload::{HotSwapService, Routes};
+use crate::process_management::ManagedProcesses;
+use crate::router::mode_router;
+use crate::service_logs::admin_router;
 
 #[derive(Deserialize, Default, Clone)]
 struct Setup {
@@ -49,114 +64,202 @@ impl GenericSetup for Setup {
 async fn main() -> MResult<()> {
   let (reload_tx, _) = broadcast::channel::<()>(16);
 
-  let setup = load_generic_config::<Setup>("lbrp-service").await.unwrap();
+  let command = cli::Command::parse(std::env::args().skip(1));
+  let setup = load_generic_config::<Setup>("lbrp-service").await;
+  if !matches!(command, cli::Command::Serve) {
+    let setup = match setup {
+      Ok(setup) => setup,
+      Err(e) => {
+        println!("`lbrp-service.yaml` is invalid: {e}");
+        std::process::exit(1);
+      }
+    };
+    cli::run(command, setup.config_file.as_deref().unwrap_or("lbrp-config.json")).await;
+    return Ok(());
+  }
+
+  let setup = setup.unwrap();
   let state = load_generic_state(&setup, true).await.unwrap();
-  let mut children = vec![];
+  let config_file = setup.config_file.as_deref().unwrap_or("lbrp-config.json").to_owned();
 
-  loop {
-    let watcher_tx = reload_tx.clone();
+  let watcher_tx = reload_tx.clone();
+  let watched_file = config_file.clone();
+  let watcher_handle = tokio::spawn(async move {
+    if let Err(e) = config_watcher(watched_file, watcher_tx).await {
+      eprintln!("Config watcher error: {e:?}");
+    }
+  });
+  let mut reload_rx = reload_tx.subscribe();
+
+  let config = match load_config(&config_file) {
+    Ok(config) => config,
+    Err(e) => {
+      tracing::error!("Can't get the config due to: {}!", e);
+      std::process::exit(1);
+    }
+  };
+  if let LbrpMode::Supervisor = config.lbrp_mode {
+    return run_supervisor(config, &config_file, reload_rx, watcher_handle).await;
+  }
 
-    let config_file = setup.config_file.as_deref().unwrap_or("lbrp-config.json").to_owned();
-    let watcher_handle = tokio::spawn(async move {
-      if let Err(e) = config_watcher(config_file, watcher_tx).await {
-        eprintln!("Config watcher error: {e:?}");
+  let root_router = || get_root_router_autoinject(&state, setup.clone());
+  let mut processes = ManagedProcesses::default();
+  let mut runtime = ClusterRuntime::default();
+  let routes = build_routes(&config, &config_file, root_router(), &mut processes, &mut runtime).await?;
+  let hot_service = HotSwapService::new(routes);
+
+  // Listeners are started once; reloads only swap the service behind them.
+  let reloads = async {
+    loop {
+      match reload_rx.recv().await {
+        Ok(()) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+        Err(broadcast::error::RecvError::Closed) => return Ok::<(), ServerError>(()),
       }
-    });
-
-    let mut reload_rx = reload_tx.subscribe();
-
-    let file = std::fs::File::open(setup.config_file.as_deref().unwrap_or("lbrp-config.json")).map_err(|e| {
-      ServerError::from_private(e)
-        .with_public("Can't open `lbrp-config.json`!")
-        .with_500()
-    })?;
-    let reader = std::io::BufReader::new(file);
-    let config = match serde_json::from_reader::<_, LbrpConfig>(reader) {
-      Ok(config) => {
-        if let Err(e) = config.validate() {
-          tracing::info!("Can't get the config due to: {}!", e);
-          std::process::exit(1);
+      tracing::info!("Server is going to reload...");
+
+      let config = match load_config(&config_file) {
+        Ok(config) => config,
+        Err(e) => {
+          tracing::error!("Can't apply the new config due to: {}! Keep serving the previous one.", e);
+          continue;
         }
-        config
+      };
+      if let LbrpMode::Supervisor = config.lbrp_mode {
+        tracing::warn!("Switching to the supervisor mode requires restart, the config is not applied!");
+        continue;
       }
-      Err(e) => {
-        tracing::info!("Can't get the config due to: {}!", e);
-        std::process::exit(1);
+
+      match build_routes(&config, &config_file, root_router(), &mut processes, &mut runtime).await {
+        Ok(routes) => {
+          hot_service.swap(routes);
+          tracing::info!("Server is reloaded.");
+        }
+        Err(e) => tracing::error!("Can't apply the new config due to: {}! Keep serving the previous one.", e),
       }
-    };
+    }
+  };
 
-    let lbrp_router = get_root_router_autoinject(&state, setup.clone())
-      .hoop(affix_state::inject(init_authcli().await?))
-      .push(get_router_from_config(&config, &mut children).await);
+  if matches!(state.startup_variant, StartupVariant::HttpsOnly)
+    || matches!(state.startup_variant, StartupVariant::Quinn)
+    || matches!(state.startup_variant, StartupVariant::QuinnOnly)
+  {
+    let (server, handle) = start_with_service(state.clone(), &setup, hot_service.as_service()).await.unwrap();
+    let (http_server, http_handle) = start_force_https_redirect(80, 443).await.unwrap();
+    let default_handle = tokio::spawn(async move { default_shutdown_signal(handle, Some(http_handle)).await });
 
-    tracing::info!("Router:\n{:?}", lbrp_router);
+    tracing::info!("Server is booted.");
 
-    let mut lbrp_service = salvo::Service::new(lbrp_router);
+    select! {
+      _ = server => tracing::info!("Server is shutdowned."),
+      _ = http_server => tracing::info!("Server is shutdowned."),
+      res = reloads => res?,
+      _ = default_handle => std::process::exit(0),
+      res = watcher_handle => tracing::info!("Watcher handle is stopped with result `{:?}`! Exit...", res),
+    }
+  } else {
+    let (server, handle) = start_with_service(state.clone(), &setup, hot_service.as_service()).await.unwrap();
+    let default_handle = tokio::spawn(async move { default_shutdown_signal(handle, None).await });
 
-    if config.services.iter().any(|s| matches!(s, Service::ErrorHandler(_))) {
-      lbrp_service = lbrp_service.catcher(salvo::catcher::Catcher::new(ErrHandler::new(
-        crate::router::excluded_from_err_handling(&config.services),
-      )));
+    tracing::info!("Server is booted.");
+
+    select! {
+      _ = server => tracing::info!("Server is shutdowned."),
+      res = reloads => res?,
+      _ = default_handle => std::process::exit(0),
+      res = watcher_handle => tracing::info!("Watcher handle is stopped with result `{:?}`! Exit...", res),
     }
+  }
 
-    if matches!(state.startup_variant, StartupVariant::HttpsOnly)
-      || matches!(state.startup_variant, StartupVariant::Quinn)
-      || matches!(state.startup_variant, StartupVariant::QuinnOnly)
-    {
-      let custom_shutdown = move |handle: ServerHandle, http_handle: ServerHandle| async move {
-        if reload_rx.recv().await.is_ok() {
-          handle.stop_graceful(Duration::from_secs(10));
-          http_handle.stop_graceful(Duration::from_secs(10));
-        }
-      };
+  runtime.stop().await;
+  Ok(())
+}
 
-      let (server, handle) = start_with_service(state.clone(), &setup, lbrp_service).await.unwrap();
-      let (http_server, http_handle) = start_force_https_redirect(80, 443).await.unwrap();
-
-      let h1 = handle.clone();
-      let h2 = handle.clone();
-      let http_h1 = http_handle.clone();
-      let http_h2 = http_handle.clone();
-      let custom_handle = tokio::spawn(async move { custom_shutdown(h1, http_h1).await });
-      let default_handle = tokio::spawn(async move { default_shutdown_signal(h2, Some(http_h2)).await });
-
-      tracing::info!("Server is booted.");
-
-      select! {
-        _ = server => tracing::info!("Server is shutdowned."),
-        _ = http_server => tracing::info!("Server is shutdowned."),
-        _ = custom_handle => tracing::info!("Server is going to reload..."),
-        _ = default_handle => std::process::exit(0),
-        res = watcher_handle => {
-          tracing::info!("Watcher handle is stopped with result `{:?}`! Exit...", res);
-          return Ok(())
-        },
-      }
-    } else {
-      let custom_shutdown = move |handle: ServerHandle| async move {
-        if reload_rx.recv().await.is_ok() {
-          handle.stop_graceful(Duration::from_secs(10));
-        }
-      };
+/// Builds the routes for the config, syncing service processes and restarting cluster tasks in `runtime`.
+///
+/// Fails before touching processes and tasks, so the previous routes can be kept.
+async fn build_routes(
+  config: &LbrpConfig,
+  config_file: &str,
+  root_router: Router,
+  processes: &mut ManagedProcesses,
+  runtime: &mut ClusterRuntime,
+) -> MResult<Routes> {
+  let authcli = init_authcli().await?;
+  std::mem::take(runtime).stop().await;
+
+  match &config.lbrp_mode {
+    LbrpMode::PC(LbrpPCMode::Parent) | LbrpMode::Ybob(LbrpYBOBMode::OlderBrother) => processes.stop_all().await,
+    _ => processes.sync(config.common_services()).await,
+  }
+  match &config.lbrp_mode {
+    LbrpMode::PC(LbrpPCMode::Child) => cluster::pc::spawn_child_heartbeat(config, runtime),
+    LbrpMode::Ybob(LbrpYBOBMode::YoungerBrother) => cluster::ybob::spawn_younger_heartbeat(config, runtime),
+    _ => {}
+  }
+  let app_router = mode_router(config, runtime).await;
+  cluster::supervisor::start_managed_node(config, config_file, runtime);
+  if let Some(listener) = &config.proxy_protocol {
+    runtime.spawn(proxy_protocol::serve_listener(listener.clone()));
+  }
+  // Children of a parent serve stream services themselves.
+  for service in config
+    .services
+    .iter()
+    .filter(|_| !matches!(config.lbrp_mode, LbrpMode::PC(LbrpPCMode::Parent)))
+  {
+    match service {
+      Service::TcpStream(stream) => runtime.spawn(stream_proxy::serve_tcp(stream.clone())),
+      Service::UdpStream(stream) => runtime.spawn(stream_proxy::serve_udp(stream.clone())),
+      _ => {}
+    }
+  }
+  runtime.start_control_server(config.cluster.as_ref());
+
+  let mut lbrp_router = root_router
+    .hoop(proxy_protocol::RestoreClientAddr)
+    .hoop(affix_state::inject(authcli));
+  match &config.admin_secret {
+    Some(secret) => lbrp_router = lbrp_router.push(admin_router(secret.clone(), processes.outputs())),
+    None => tracing::info!("Admin routes are disabled: `admin_secret` isn't specified."),
+  }
+  let lbrp_router = lbrp_router.push(app_router);
+
+  tracing::info!("Router:\n{:?}", lbrp_router);
 
-      let (server, handle) = start_with_service(state.clone(), &setup, lbrp_service).await.unwrap();
+  let catcher = config
+    .services
+    .iter()
+    .any(|s| matches!(s, Service::ErrorHandler(_)))
+    .then(|| ErrHandler::new(crate::router::excluded_from_err_handling(&config.services)));
 
-      let h1 = handle.clone();
-      let h2 = handle.clone();
-      let custom_handle = tokio::spawn(async move { custom_shutdown(h1).await });
-      let default_handle = tokio::spawn(async move { default_shutdown_signal(h2, None).await });
+  Ok(Routes { router: lbrp_router, catcher })
+}
 
-      tracing::info!("Server is booted.");
+/// Delivers configs to managed nodes; the supervisor node doesn't serve any traffic.
+async fn run_supervisor(
+  mut config: LbrpConfig,
+  config_file: &str,
+  mut reload_rx: broadcast::Receiver<()>,
+  mut watcher_handle: JoinHandle<()>,
+) -> MResult<()> {
+  tracing::info!("Supervisor is booted.");
+  loop {
+    select! {
+      _ = cluster::supervisor::supervise(&config) => continue,
+      _ = reload_rx.recv() => tracing::info!("Supervisor is going to reload..."),
+      _ = tokio::signal::ctrl_c() => std::process::exit(0),
+      res = &mut watcher_handle => {
+        tracing::info!("Watcher handle is stopped with result `{:?}`! Exit...", res);
+        return Ok(())
+      },
+    }
 
-      select! {
-        _ = server => tracing::info!("Server is shutdowned."),
-        _ = custom_handle => tracing::info!("Server is going to reload..."),
-        _ = default_handle => std::process::exit(0),
-        res = watcher_handle => {
-          tracing::info!("Watcher handle is stopped with result `{:?}`! Exit...", res);
-          return Ok(())
-        },
+    match load_config(config_file) {
+      Ok(new_config) if matches!(new_config.lbrp_mode, LbrpMode::Supervisor) => config = new_config,
+      Ok(_) => {
+        tracing::warn!("Switching from the supervisor mode requires restart, the config is not applied!")
       }
+      Err(e) => tracing::error!("Can't apply the new config due to: {}! Keep the previous one.", e),
     }
   }
 }
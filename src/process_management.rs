@@ -0,0 +1,552 @@
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::path::{Path, PathBuf};
+use std::process::ExitStatus;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::process::Child;
+use tokio::select;
+use tokio::sync::{broadcast, watch};
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+use crate::config::{CommonService, ProcessSpec, ReadinessProbe, unix_socket_path};
+use crate::proxy_client::socket_get;
+use crate::service_logs::{ServiceOutput, ServiceOutputs};
+
+/// Interval between readiness probes of a started process.
+const READINESS_POLL: Duration = Duration::from_millis(200);
+/// Timeout of a single HTTP readiness probe.
+const READINESS_HTTP_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// State of a service process, watched by dependent services and by config reload.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ProcessState {
+  /// Waiting for dependencies or for the readiness probe.
+  Starting,
+  Ready,
+  /// Some dependency has failed, or the process is not ready in time or not running.
+  Failed,
+}
+
+/// Supervisor task of a service process.
+struct ManagedProcess {
+  spec: ProcessSpec,
+  stop_tx: watch::Sender<bool>,
+  state_rx: watch::Receiver<ProcessState>,
+  task: JoinHandle<()>,
+}
+
+/// Processes of common services with `startup_cmd`, keyed by service name.
+#[derive(Default)]
+pub(crate) struct ManagedProcesses {
+  processes: HashMap<String, ManagedProcess>,
+  outputs: ServiceOutputs,
+}
+
+/// Reads `KEY=VALUE` lines, skipping empty lines and `#` comments.
+fn read_env_file(path: &Path) -> std::io::Result<Vec<(String, String)>> {
+  let data = std::fs::read_to_string(path)?;
+  let mut vars = vec![];
+  for line in data.lines().map(str::trim) {
+    if line.is_empty() || line.starts_with('#') {
+      continue;
+    }
+    let line = line.strip_prefix("export ").unwrap_or(line);
+    let Some((key, value)) = line.split_once('=') else {
+      return Err(std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("`{}` has a line without `=`", path.display()),
+      ));
+    };
+    let value = value.trim();
+    let value = value
+      .strip_prefix('"')
+      .and_then(|v| v.strip_suffix('"'))
+      .or(value.strip_prefix('\'').and_then(|v| v.strip_suffix('\'')))
+      .unwrap_or(value);
+    vars.push((key.trim().to_owned(), value.to_owned()));
+  }
+  Ok(vars)
+}
+
+/// Buffer size for `getpwnam_r` and `getgrnam_r` records.
+const USER_DB_BUF_SIZE: usize = 16 * 1024;
+
+/// Resolves a user name or id to the uid and the primary group id; ids without a passwd entry have no group.
+pub(crate) fn lookup_user(user: &str) -> Option<(libc::uid_t, Option<libc::gid_t>)> {
+  // SAFETY: `passwd` is a plain C struct which is valid when zeroed.
+  let mut passwd = unsafe { std::mem::zeroed::<libc::passwd>() };
+  let mut buf = vec![0 as libc::c_char; USER_DB_BUF_SIZE];
+  let mut result = std::ptr::null_mut();
+  let code = match user.parse::<libc::uid_t>() {
+    // SAFETY: every pointer is valid during the call and `buf` is not used after it.
+    Ok(uid) => unsafe { libc::getpwuid_r(uid, &mut passwd, buf.as_mut_ptr(), buf.len(), &mut result) },
+    Err(_) => {
+      let name = CString::new(user).ok()?;
+      // SAFETY: every pointer is valid during the call and `buf` is not used after it.
+      unsafe { libc::getpwnam_r(name.as_ptr(), &mut passwd, buf.as_mut_ptr(), buf.len(), &mut result) }
+    }
+  };
+  if code == 0 && !result.is_null() {
+    Some((passwd.pw_uid, Some(passwd.pw_gid)))
+  } else {
+    user.parse::<libc::uid_t>().ok().map(|uid| (uid, None))
+  }
+}
+
+/// Resolves a group name or id to the group id.
+pub(crate) fn lookup_group(group: &str) -> Option<libc::gid_t> {
+  if let Ok(gid) = group.parse::<libc::gid_t>() {
+    return Some(gid);
+  }
+  let name = CString::new(group).ok()?;
+  // SAFETY: `group` is a plain C struct which is valid when zeroed.
+  let mut record = unsafe { std::mem::zeroed::<libc::group>() };
+  let mut buf = vec![0 as libc::c_char; USER_DB_BUF_SIZE];
+  let mut result = std::ptr::null_mut();
+  // SAFETY: every pointer is valid during the call and `buf` is not used after it.
+  let code = unsafe { libc::getgrnam_r(name.as_ptr(), &mut record, buf.as_mut_ptr(), buf.len(), &mut result) };
+  (code == 0 && !result.is_null()).then_some(record.gr_gid)
+}
+
+pub(crate) fn parse_umask(umask: &str) -> Option<libc::mode_t> {
+  libc::mode_t::from_str_radix(umask, 8)
+    .ok()
+    .filter(|mask| *mask <= 0o777)
+}
+
+fn spawn(spec: &ProcessSpec) -> std::io::Result<Child> {
+  let invalid = |msg: String| std::io::Error::new(std::io::ErrorKind::InvalidInput, msg);
+
+  let mut cmd = tokio::process::Command::new(&spec.startup_cmd);
+  cmd
+    .args(&spec.startup_args)
+    .current_dir(&spec.working_dir)
+    .stdout(std::process::Stdio::piped())
+    .stderr(std::process::Stdio::piped())
+    .kill_on_drop(true);
+
+  // Relative to `working_dir`, see `CommonService::env_file_path`.
+  if let Some(env_file) = &spec.env_file {
+    cmd.envs(read_env_file(env_file)?);
+  }
+  cmd.envs(&spec.env);
+
+  let mut gid = None;
+  if let Some(user) = &spec.user {
+    let (uid, primary_gid) = lookup_user(user).ok_or_else(|| invalid(format!("unknown user `{user}`")))?;
+    cmd.uid(uid);
+    gid = primary_gid;
+  }
+  if let Some(group) = &spec.group {
+    gid = Some(lookup_group(group).ok_or_else(|| invalid(format!("unknown group `{group}`")))?);
+  }
+  // Otherwise the process would keep the group of lbrp.
+  if let Some(user) = &spec.user
+    && gid.is_none()
+  {
+    return Err(invalid(format!("user `{user}` has no primary group, set `group`")));
+  }
+  if let Some(gid) = gid {
+    cmd.gid(gid);
+  }
+  if let Some(umask) = &spec.umask {
+    let mask = parse_umask(umask).ok_or_else(|| invalid(format!("invalid umask `{umask}`")))?;
+    // SAFETY: `umask` is async-signal-safe.
+    unsafe {
+      cmd.pre_exec(move || {
+        libc::umask(mask);
+        Ok(())
+      });
+    }
+  }
+
+  cmd.spawn()
+}
+
+async fn wait_connectable(addrs: &[String]) {
+  for addr in addrs {
+    while tokio::net::TcpStream::connect(addr).await.is_err() {
+      tokio::time::sleep(READINESS_POLL).await;
+    }
+  }
+}
+
+async fn wait_socket_connectable(sockets: &[PathBuf]) {
+  for socket in sockets {
+    while tokio::net::UnixStream::connect(socket).await.is_err() {
+      tokio::time::sleep(READINESS_POLL).await;
+    }
+  }
+}
+
+/// Waits `wait_after` and then until the readiness probe of the process succeeds.
+async fn wait_ready(spec: &ProcessSpec, mut lines: broadcast::Receiver<String>) {
+  tokio::time::sleep(spec.wait_after).await;
+  match &spec.readiness {
+    None | Some(ReadinessProbe::Tcp { addr: None }) => {
+      wait_connectable(&spec.listen_addrs()).await;
+      wait_socket_connectable(&spec.socket_paths()).await;
+    }
+    Some(ReadinessProbe::Tcp { addr: Some(addr) }) => wait_connectable(std::slice::from_ref(addr)).await,
+    Some(ReadinessProbe::Http { path }) => {
+      let Some(upstream) = spec.upstreams.first() else {
+        return;
+      };
+      if let Some(socket) = unix_socket_path(upstream, Some(&spec.working_dir)) {
+        while !tokio::time::timeout(READINESS_HTTP_TIMEOUT, socket_get(&socket, path))
+          .await
+          .is_ok_and(|res| res.is_ok_and(|status| status.is_success()))
+        {
+          tokio::time::sleep(READINESS_POLL).await;
+        }
+        return;
+      }
+      let url = format!("{}{path}", upstream.trim_end_matches('/'));
+      let client = reqwest::Client::builder()
+        .timeout(READINESS_HTTP_TIMEOUT)
+        .build()
+        .unwrap();
+      while !client.get(&url).send().await.is_ok_and(|res| res.status().is_success()) {
+        tokio::time::sleep(READINESS_POLL).await;
+      }
+    }
+    Some(ReadinessProbe::LogLine { pattern }) => {
+      let Ok(pattern) = regex::Regex::new(pattern) else {
+        return;
+      };
+      loop {
+        match lines.recv().await {
+          Ok(line) if pattern.is_match(&line) => return,
+          Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+          Err(broadcast::error::RecvError::Closed) => std::future::pending::<()>().await,
+        }
+      }
+    }
+  }
+}
+
+/// Waits for the process to exit, reporting its readiness in the meantime.
+async fn wait_exit(
+  spec: &ProcessSpec,
+  child: &mut Child,
+  lines: broadcast::Receiver<String>,
+  state_tx: &watch::Sender<ProcessState>,
+) -> Option<ExitStatus> {
+  let ready = wait_ready(spec, lines);
+  let deadline = tokio::time::sleep(spec.wait_after + spec.ready_timeout);
+  tokio::pin!(ready, deadline);
+  let mut timed_out = false;
+  loop {
+    select! {
+      status = child.wait() => return status.ok(),
+      _ = &mut ready => {
+        tracing::info!("Service process is ready");
+        state_tx.send_replace(ProcessState::Ready);
+        break;
+      }
+      _ = &mut deadline, if !timed_out => {
+        tracing::warn!("Service process is not ready in time");
+        state_tx.send_replace(ProcessState::Failed);
+        timed_out = true;
+      }
+    }
+  }
+  child.wait().await.ok()
+}
+
+/// Waits until every dependency is ready; the service is marked failed while some dependency has failed.
+async fn wait_dependencies(
+  dependencies: Vec<(String, watch::Receiver<ProcessState>)>,
+  state_tx: &watch::Sender<ProcessState>,
+) {
+  for (dependency, mut state_rx) in dependencies {
+    tracing::info!(dependency, "Waiting for dependency");
+    if !matches!(
+      state_rx.wait_for(|s| *s != ProcessState::Starting).await.as_deref(),
+      Ok(ProcessState::Ready)
+    ) {
+      tracing::error!(dependency, "Dependency has failed, waiting for it to become ready");
+      state_tx.send_replace(ProcessState::Failed);
+      if state_rx.wait_for(|s| *s == ProcessState::Ready).await.is_err() {
+        std::future::pending::<()>().await;
+      }
+      state_tx.send_replace(ProcessState::Starting);
+    }
+  }
+}
+
+/// Stops the process with `SIGTERM`, and with `SIGKILL` if it doesn't exit in `stop_timeout`.
+async fn stop_child(child: &mut Child, stop_timeout: Duration) {
+  tracing::info!("Stopping service process");
+  if let Some(pid) = child.id() {
+    // SAFETY: `pid` belongs to the child which is not reaped yet.
+    unsafe {
+      libc::kill(pid as libc::pid_t, libc::SIGTERM);
+    }
+    if tokio::time::timeout(stop_timeout, child.wait()).await.is_ok() {
+      tracing::info!("Service process is stopped");
+      return;
+    }
+    tracing::warn!("Service process didn't stop in time, killing it");
+  }
+  if let Err(e) = child.kill().await {
+    tracing::error!(error = ?e, "Can't kill service process");
+  }
+}
+
+/// Runs the process and restarts it by the restart policy until stop is requested.
+#[tracing::instrument(skip_all, name = "service-process", fields(service = service_name))]
+async fn supervise(
+  service_name: String,
+  spec: ProcessSpec,
+  dependencies: Vec<(String, watch::Receiver<ProcessState>)>,
+  mut stop_rx: watch::Receiver<bool>,
+  state_tx: watch::Sender<ProcessState>,
+  output: Arc<ServiceOutput>,
+) {
+  select! {
+    _ = wait_dependencies(dependencies, &state_tx) => {}
+    _ = stop_rx.changed() => return,
+  }
+
+  let mut restarts = 0;
+  loop {
+    state_tx.send_replace(ProcessState::Starting);
+    let started = Instant::now();
+    // Subscribes before the start, so no line is missed by the readiness probe.
+    let lines = output.subscribe();
+    let status = match spawn(&spec) {
+      Ok(mut child) => {
+        tracing::info!(pid = child.id(), "Service process is started");
+        output.capture(&mut child);
+        let status = select! {
+          status = wait_exit(&spec, &mut child, lines, &state_tx) => status,
+          _ = stop_rx.changed() => {
+            stop_child(&mut child, spec.stop_timeout).await;
+            return;
+          }
+        };
+        state_tx.send_replace(ProcessState::Failed);
+        match &status {
+          Some(status) => tracing::warn!(%status, "Service process has exited"),
+          None => tracing::error!("Service process is lost"),
+        }
+        status
+      }
+      Err(e) => {
+        tracing::error!(error = ?e, "Can't start service process");
+        state_tx.send_replace(ProcessState::Failed);
+        None
+      }
+    };
+
+    if !spec.restart.should_restart(status.as_ref()) {
+      tracing::info!("Service process won't be restarted by its restart policy");
+      return;
+    }
+    // The process which has worked for a while is not considered crashing in a row.
+    if started.elapsed() >= spec.restart.reset_after() {
+      restarts = 0;
+    }
+    if spec.restart.max_restarts.is_some_and(|max| restarts >= max) {
+      tracing::error!(restarts, "Service process is given up after too many restarts");
+      return;
+    }
+
+    let delay = spec.restart.backoff(restarts);
+    restarts += 1;
+    tracing::info!(
+      restarts,
+      delay_ms = delay.as_millis() as u64,
+      "Restarting service process"
+    );
+    select! {
+      _ = tokio::time::sleep(delay) => {}
+      _ = stop_rx.changed() => return,
+    }
+  }
+}
+
+async fn stop_process(service_name: &str, process: ManagedProcess) {
+  tracing::debug!(service = service_name, "Stopping service supervisor");
+  let _ = process.stop_tx.send(true);
+  if let Err(e) = process.task.await {
+    tracing::error!(service = service_name, error = ?e, "Service supervisor has failed");
+  }
+}
+
+impl ManagedProcesses {
+  /// Starts new services, restarts changed ones and stops the ones which are not listed anymore.
+  ///
+  /// Processes of services with unchanged [`ProcessSpec`] are kept running. New processes are started in parallel,
+  /// each one after its `depends_on` services are ready. Returns when started processes are ready or have failed.
+  pub(crate) async fn sync<'a>(&mut self, services: impl Iterator<Item = &'a CommonService>) {
+    let mut wanted = HashMap::new();
+    let mut depends_on = HashMap::new();
+    for service in services {
+      if let Some(spec) = service.process_spec() {
+        wanted.insert(service.service_name.clone(), spec);
+        depends_on.insert(
+          service.service_name.clone(),
+          service.depends_on.clone().unwrap_or_default(),
+        );
+      }
+    }
+
+    let stale = self
+      .processes
+      .iter()
+      .filter(|(name, process)| wanted.get(*name).is_none_or(|spec| *spec != process.spec))
+      .map(|(name, _)| name.clone())
+      .collect::<Vec<_>>();
+    let stale = stale
+      .into_iter()
+      .filter_map(|name| {
+        self.outputs.remove(&name);
+        self.processes.remove(&name).map(|process| (name, process))
+      })
+      .collect::<Vec<_>>();
+    futures_util::future::join_all(stale.into_iter().map(|(name, process)| async move {
+      stop_process(&name, process).await;
+    }))
+    .await;
+
+    // States of all new processes are created first, so dependencies started together could be awaited.
+    let mut new_states = HashMap::new();
+    for name in wanted.keys() {
+      if self.processes.contains_key(name) {
+        tracing::debug!(service = name, "Service process is unchanged");
+      } else {
+        new_states.insert(name.clone(), watch::channel(ProcessState::Starting));
+      }
+    }
+    let state_of = |name: &str| {
+      self
+        .processes
+        .get(name)
+        .map(|p| p.state_rx.clone())
+        .or(new_states.get(name).map(|(_, state_rx)| state_rx.clone()))
+    };
+    let dependencies = new_states
+      .keys()
+      .map(|name| {
+        let dependencies = depends_on
+          .get(name)
+          .into_iter()
+          .flatten()
+          .filter_map(|dependency| state_of(dependency).map(|state_rx| (dependency.clone(), state_rx)))
+          .collect::<Vec<_>>();
+        (name.clone(), dependencies)
+      })
+      .collect::<HashMap<_, _>>();
+
+    let mut started = vec![];
+    for (name, (state_tx, state_rx)) in new_states {
+      let Some(spec) = wanted.remove(&name) else {
+        continue;
+      };
+      let (stop_tx, stop_rx) = watch::channel(false);
+      let output = Arc::new(ServiceOutput::new(&name, &spec.logs));
+      self.outputs.insert(name.clone(), output.clone());
+      let task = tokio::spawn(supervise(
+        name.clone(),
+        spec.clone(),
+        dependencies.get(&name).cloned().unwrap_or_default(),
+        stop_rx,
+        state_tx,
+        output,
+      ));
+      started.push(state_rx.clone());
+      self.processes.insert(
+        name,
+        ManagedProcess {
+          spec,
+          stop_tx,
+          state_rx,
+          task,
+        },
+      );
+    }
+
+    futures_util::future::join_all(started.into_iter().map(|mut state_rx| async move {
+      let _ = state_rx.wait_for(|s| *s != ProcessState::Starting).await;
+    }))
+    .await;
+  }
+
+  /// Outputs of running services, for the logs endpoint.
+  pub(crate) fn outputs(&self) -> ServiceOutputs {
+    self.outputs.clone()
+  }
+
+  pub(crate) async fn stop_all(&mut self) {
+    let outputs = &self.outputs;
+    futures_util::future::join_all(self.processes.drain().map(|(name, process)| async move {
+      outputs.remove(&name);
+      stop_process(&name, process).await;
+    }))
+    .await;
+  }
+}
+
+impl Drop for ManagedProcesses {
+  fn drop(&mut self) {
+    // Dropping the supervisors kills their processes.
+    for process in self.processes.values() {
+      process.task.abort();
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn reads_env_file() {
+    let path = std::env::temp_dir().join(format!("lbrp-env-{}", std::process::id()));
+    std::fs::write(
+      &path,
+      "# secrets\n\nexport TOKEN=abc\nNAME = \"quoted value\"\nSINGLE='single'\nEMPTY=\n",
+    )
+    .unwrap();
+    let vars = read_env_file(&path);
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(
+      vars.unwrap(),
+      vec![
+        ("TOKEN".to_owned(), "abc".to_owned()),
+        ("NAME".to_owned(), "quoted value".to_owned()),
+        ("SINGLE".to_owned(), "single".to_owned()),
+        ("EMPTY".to_owned(), String::new()),
+      ]
+    );
+  }
+
+  #[test]
+  fn rejects_env_lines_without_value() {
+    let path = std::env::temp_dir().join(format!("lbrp-env-invalid-{}", std::process::id()));
+    std::fs::write(&path, "TOKEN\n").unwrap();
+    let vars = read_env_file(&path);
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(vars.unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+  }
+
+  #[test]
+  fn user_ids_without_passwd_entry_have_no_group() {
+    assert_eq!(lookup_user("0"), Some((0, Some(0))));
+    assert_eq!(lookup_user("4000000000"), Some((4_000_000_000, None)));
+    assert_eq!(lookup_user("no-such-lbrp-user"), None);
+  }
+
+  #[test]
+  fn parses_umask() {
+    assert_eq!(parse_umask("027"), Some(0o027));
+    assert_eq!(parse_umask("0777"), Some(0o777));
+    assert_eq!(parse_umask("1000"), None);
+    assert_eq!(parse_umask("089"), None);
+    assert_eq!(parse_umask(""), None);
+  }
+}
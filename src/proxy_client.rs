@@ -1,24 +1,143 @@
-use futures_util::TryStreamExt;
+use futures_util::stream::BoxStream;
+use futures_util::{StreamExt, TryStreamExt};
+use http_body_util::{BodyDataStream, Empty, StreamBody};
+use hyper::body::{Body, Bytes, Frame};
 use hyper::header::{CONNECTION, UPGRADE};
 use hyper::upgrade::OnUpgrade;
 use hyper::{HeaderMap, StatusCode};
 use impulse_server_kit::prelude::*;
 use impulse_server_kit::tracing::Instrument;
 use reqwest::Client as ReqwestCli;
+use salvo::http::header::HeaderName;
 use salvo::http::{HeaderValue, ReqBody, ResBody};
 use salvo::hyper;
 use salvo::proxy::{Client as ProxyCli, Proxy, Upstreams};
 use salvo::rt::tokio::TokioIo;
-use tokio::io::copy_bidirectional_with_sizes;
+use salvo::{Handler, Writer};
+use std::collections::HashMap;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, LazyLock, Mutex, Weak};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, copy_bidirectional_with_sizes};
+use tokio::net::{TcpStream, UnixStream};
+
+use crate::cluster::request_host;
+use crate::config::{
+  BalancingStrategy, CircuitBreaker, CommonService, ConnectionPool, HashKey, HealthCheck, ProxyProtocolVersion,
+  RetryOn, RetryPolicy, UpstreamList, unix_socket_path,
+};
+use crate::error_handling::{GATEWAY_ERROR_HEADER, GatewayError, report_gateway_error};
+use crate::header_handling::{client_addr, client_ip};
+use crate::proxy_protocol::encode_header;
+use crate::router::{HostPattern, PathMatcher, fill_placeholders};
 
 #[derive(Clone, Debug)]
 pub(crate) struct ModifiedReqwestClient {
-  inner: ReqwestCli,
+  inner: Arc<ReqwestCli>,
   domain: String,
+  pool: Option<Arc<UpstreamPool>>,
+  policy: Arc<RequestPolicy>,
+  proxy_header: Option<Vec<u8>>,
+  /// Upstream elected by the handler, and host captures filling placeholders of the next upstream on retries.
+  node: Option<(usize, Vec<(String, String)>)>,
+  sockets: Option<Arc<SocketConnections>>,
+}
+
+/// Timeouts and retries of requests to upstreams of a service.
+#[derive(Debug, Default)]
+pub(crate) struct RequestPolicy {
+  connect_timeout: Option<Duration>,
+  request_timeout: Option<Duration>,
+  idle_timeout: Option<Duration>,
+  max_body_size: Option<u64>,
+  retry: Option<RetryPolicy>,
+}
+
+impl RequestPolicy {
+  pub(crate) fn new(service: &CommonService) -> Self {
+    Self {
+      connect_timeout: service.connect_timeout.map(Duration::from_secs),
+      request_timeout: service.request_timeout.map(Duration::from_secs),
+      idle_timeout: service.idle_timeout.map(Duration::from_secs),
+      max_body_size: service.max_body_size,
+      retry: service.retry.clone(),
+    }
+  }
+}
+
+/// Virtual nodes per weight unit on the consistent hash ring.
+const HASH_RING_VNODES: u32 = 64;
+/// `Retry-After` value when every upstream is marked down by active health checks.
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(5);
+/// Largest body kept in memory for retries if `max_body_size` is not specified; other requests are sent once.
+const DEFAULT_RETRY_BODY_SIZE: u64 = 1024 * 1024;
+
+#[derive(Debug, Clone, Copy)]
+enum BreakerState {
+  Closed,
+  Open {
+    until: Instant,
+  },
+  /// A trial request is in flight; if its outcome isn't reported until the deadline, e.g. the client has gone away,
+  /// the upstream is tried again rather than ejected forever.
+  HalfOpen {
+    until: Instant,
+  },
 }
 
+#[derive(Debug)]
+pub(crate) struct UpstreamNode {
+  pub(crate) url: String,
+  /// URL requests are proxied to; `unix:` upstreams get a synthetic `http` one.
+  pub(crate) target: String,
+  /// Socket of a `unix:` upstream.
+  pub(crate) socket: Option<PathBuf>,
+  pub(crate) weight: u32,
+  active_connections: AtomicUsize,
+  healthy: AtomicBool,
+  failures: AtomicU32,
+  breaker: Mutex<BreakerState>,
+}
+
+/// Upstreams of a single service with the selected balancing strategy.
+#[derive(Debug)]
+pub(crate) struct UpstreamPool {
+  nodes: Vec<UpstreamNode>,
+  strategy: BalancingStrategy,
+  cursor: AtomicUsize,
+  total_weight: usize,
+  ring: Vec<(u64, usize)>,
+  breaker: Option<CircuitBreaker>,
+  health_checked: AtomicBool,
+}
+
+/// Custom [`Upstreams`] which elects an upstream from the [`UpstreamPool`].
+#[derive(Clone, Debug)]
+pub(crate) struct Balancer {
+  pool: Arc<UpstreamPool>,
+}
+
+/// Decrements the active connections counter of an upstream when dropped.
+pub(crate) struct ConnectionGuard {
+  pool: Arc<UpstreamPool>,
+  idx: usize,
+}
+
+/// Provides the client IP in a custom header.
 pub(crate) struct ProxyProvider {
-  pub header_name: String,
+  header_name: HeaderName,
+}
+
+impl ProxyProvider {
+  /// The header name is checked on config load.
+  pub(crate) fn new(header_name: &str) -> Option<Self> {
+    Some(Self {
+      header_name: HeaderName::from_bytes(header_name.as_bytes()).ok()?,
+    })
+  }
 }
 
 const COPY_BIDIRECTIONAL_BUF_SIZE: usize = 16 * 1024 * 1024;
@@ -35,37 +154,395 @@ impl impulse_server_kit::salvo::Handler for ProxyProvider {
     )
   )]
   async fn handle(&self, req: &mut Request, depot: &mut Depot, res: &mut Response, ctrl: &mut salvo::FlowCtrl) {
-    let from_ip = req.remote_addr().to_string();
-    let hname = self.header_name.clone();
-    req.headers_mut().insert(
-      Box::leak(hname.into_boxed_str()) as &str,
-      HeaderValue::from_str(&from_ip).unwrap(),
-    );
+    if let Some(ip) = client_ip(req) {
+      req.headers_mut().insert(
+        self.header_name.clone(),
+        HeaderValue::from_str(&ip.to_string()).unwrap(),
+      );
+    }
     ctrl.call_next(req, depot, res).await;
   }
 }
 
+fn hash_of<T: Hash + ?Sized>(value: &T) -> u64 {
+  let mut hasher = DefaultHasher::new();
+  value.hash(&mut hasher);
+  hasher.finish()
+}
+
+fn cookie_value<'a>(req: &'a Request, name: &str) -> Option<&'a str> {
+  req
+    .headers()
+    .get_all(hyper::header::COOKIE)
+    .iter()
+    .filter_map(|v| v.to_str().ok())
+    .flat_map(|v| v.split(';'))
+    .filter_map(|pair| pair.trim().split_once('='))
+    .find(|(k, _)| *k == name)
+    .map(|(_, v)| v)
+}
+
+/// Response to a failure of `lbrp` itself; the body is rendered by [`GatewayErrors`] with the request id.
+///
+/// [`GatewayErrors`]: crate::error_handling::GatewayErrors
+fn gateway_error(error: GatewayError) -> Result<HyperResponse, ServerError> {
+  report_gateway_error(error);
+  hyper::Response::builder()
+    .status(error.status())
+    .body(ResBody::Once(error.message().into()))
+    .map_err(|e| {
+      ServerError::from_private(e)
+        .with_public("Can't set document body!")
+        .with_500()
+    })
+}
+
+fn service_unavailable(retry_after: Duration) -> Result<HyperResponse, ServerError> {
+  let mut response = gateway_error(GatewayError::NoUpstream)?;
+  response
+    .headers_mut()
+    .insert(hyper::header::RETRY_AFTER, retry_after.as_secs().max(1).into());
+  Ok(response)
+}
+
+impl UpstreamPool {
+  pub(crate) fn new(
+    upstreams: &UpstreamList,
+    working_dir: Option<&Path>,
+    strategy: BalancingStrategy,
+    breaker: Option<CircuitBreaker>,
+  ) -> Self {
+    let nodes = upstreams
+      .iter()
+      .enumerate()
+      .map(|(idx, u)| UpstreamNode {
+        url: u.url().to_owned(),
+        target: match unix_socket_path(u.url(), None) {
+          Some(_) => format!("http://unix-socket-{idx}.localhost"),
+          None => u.url().to_owned(),
+        },
+        socket: unix_socket_path(u.url(), working_dir),
+        weight: u.weight(),
+        active_connections: AtomicUsize::new(0),
+        healthy: AtomicBool::new(true),
+        failures: AtomicU32::new(0),
+        breaker: Mutex::new(BreakerState::Closed),
+      })
+      .collect::<Vec<_>>();
+    let total_weight = nodes.iter().map(|n| n.weight as usize).sum();
+
+    let mut ring = vec![];
+    if matches!(strategy, BalancingStrategy::ConsistentHash { .. }) {
+      for (idx, node) in nodes.iter().enumerate() {
+        for vnode in 0..(HASH_RING_VNODES * node.weight) {
+          ring.push((hash_of(&(node.url.as_str(), vnode)), idx));
+        }
+      }
+      ring.sort_unstable();
+    }
+
+    Self {
+      nodes,
+      strategy,
+      cursor: AtomicUsize::new(0),
+      total_weight,
+      ring,
+      breaker,
+      health_checked: AtomicBool::new(false),
+    }
+  }
+
+  pub(crate) fn nodes(&self) -> &[UpstreamNode] {
+    &self.nodes
+  }
+
+  /// Marks the pool as probed by active health checks; returns `false` if it already is.
+  pub(crate) fn start_health_checks(&self) -> bool {
+    !self.health_checked.swap(true, Ordering::Relaxed)
+  }
+
+  pub(crate) fn is_available(&self, idx: usize) -> bool {
+    let node = &self.nodes[idx];
+    node.healthy.load(Ordering::Relaxed)
+      && match *node.breaker.lock().unwrap() {
+        BreakerState::Closed => true,
+        BreakerState::Open { until } | BreakerState::HalfOpen { until } => Instant::now() >= until,
+      }
+  }
+
+  /// Returns how long the whole service is expected to stay unavailable, if none of upstreams can be used now.
+  pub(crate) fn unavailable_for(&self) -> Option<Duration> {
+    if (0..self.nodes.len()).any(|idx| self.is_available(idx)) {
+      return None;
+    }
+    let now = Instant::now();
+    Some(
+      self
+        .nodes
+        .iter()
+        .filter_map(|n| match *n.breaker.lock().unwrap() {
+          BreakerState::Open { until } | BreakerState::HalfOpen { until } => Some(until.saturating_duration_since(now)),
+          BreakerState::Closed => None,
+        })
+        .min()
+        .unwrap_or(DEFAULT_RETRY_AFTER),
+    )
+  }
+
+  /// Lets a trial request through an upstream whose cool-down or previous trial is over.
+  pub(crate) fn breaker_acquire(&self, idx: usize) {
+    let Some(opts) = &self.breaker else {
+      return;
+    };
+    let mut state = self.nodes[idx].breaker.lock().unwrap();
+    if let BreakerState::Open { until } | BreakerState::HalfOpen { until } = *state
+      && Instant::now() >= until
+    {
+      *state = BreakerState::HalfOpen { until: Instant::now() + opts.cool_down() };
+      tracing::info!(upstream = self.nodes[idx].url, "Circuit is half-open, sending a trial request");
+    }
+  }
+
+  pub(crate) fn report_success(&self, idx: usize) {
+    if self.breaker.is_none() {
+      return;
+    }
+    let node = &self.nodes[idx];
+    node.failures.store(0, Ordering::Relaxed);
+    let mut state = node.breaker.lock().unwrap();
+    if !matches!(*state, BreakerState::Closed) {
+      *state = BreakerState::Closed;
+      tracing::info!(upstream = node.url, "Circuit is closed");
+    }
+  }
+
+  pub(crate) fn report_failure(&self, idx: usize) {
+    let Some(opts) = &self.breaker else {
+      return;
+    };
+    let node = &self.nodes[idx];
+    let failures = node.failures.fetch_add(1, Ordering::Relaxed) + 1;
+    let mut state = node.breaker.lock().unwrap();
+    let should_open = match *state {
+      BreakerState::Closed => failures >= opts.failure_threshold(),
+      BreakerState::HalfOpen { .. } => true,
+      BreakerState::Open { .. } => false,
+    };
+    if should_open {
+      *state = BreakerState::Open {
+        until: Instant::now() + opts.cool_down(),
+      };
+      tracing::warn!(upstream = node.url, failures, "Circuit is open, ejecting upstream");
+    }
+  }
+
+  /// Marks the upstream as healthy or unhealthy; returns `true` if the state has been changed.
+  pub(crate) fn set_healthy(&self, idx: usize, healthy: bool) -> bool {
+    self.nodes[idx].healthy.swap(healthy, Ordering::Relaxed) != healthy
+  }
+
+  /// Returns the first available node starting from `start` and going round.
+  fn first_available_from(&self, start: usize) -> Option<usize> {
+    let len = self.nodes.len();
+    (0..len).map(|i| (start + i) % len).find(|idx| self.is_available(*idx))
+  }
+
+  fn round_robin(&self) -> Option<usize> {
+    if self.nodes.is_empty() {
+      return None;
+    }
+    self.first_available_from(self.cursor.fetch_add(1, Ordering::Relaxed) % self.nodes.len())
+  }
+
+  fn weighted(&self) -> Option<usize> {
+    if self.total_weight == 0 {
+      return None;
+    }
+    let mut slot = self.cursor.fetch_add(1, Ordering::Relaxed) % self.total_weight;
+    let start = self
+      .nodes
+      .iter()
+      .position(|n| {
+        if slot < n.weight as usize {
+          true
+        } else {
+          slot -= n.weight as usize;
+          false
+        }
+      })
+      .unwrap_or_default();
+    self.first_available_from(start)
+  }
+
+  fn least_connections(&self) -> Option<usize> {
+    if self.nodes.is_empty() {
+      return None;
+    }
+    let start = self.cursor.fetch_add(1, Ordering::Relaxed) % self.nodes.len();
+    (0..self.nodes.len())
+      .map(|i| (start + i) % self.nodes.len())
+      .filter(|idx| self.is_available(*idx))
+      .min_by_key(|idx| {
+        let node = &self.nodes[*idx];
+        node.active_connections.load(Ordering::Relaxed) * self.total_weight / node.weight.max(1) as usize
+      })
+  }
+
+  fn random(&self) -> Option<usize> {
+    if self.nodes.is_empty() {
+      return None;
+    }
+    self.first_available_from(rand::random_range(0..self.nodes.len()))
+  }
+
+  fn consistent_hash(&self, key: u64) -> Option<usize> {
+    if self.ring.is_empty() {
+      return None;
+    }
+    let start = self.ring.partition_point(|(h, _)| *h < key);
+    (0..self.ring.len())
+      .map(|i| self.ring[(start + i) % self.ring.len()].1)
+      .find(|idx| self.is_available(*idx))
+  }
+
+  /// Selects an upstream according to the balancing strategy; `hash_key` gives the key for the consistent hashing.
+  fn select_by(&self, hash_key: impl FnOnce(&HashKey) -> Option<u64>) -> Option<usize> {
+    match &self.strategy {
+      BalancingStrategy::RoundRobin => self.round_robin(),
+      BalancingStrategy::Weighted => self.weighted(),
+      BalancingStrategy::LeastConnections => self.least_connections(),
+      BalancingStrategy::Random => self.random(),
+      BalancingStrategy::ConsistentHash { by } => match hash_key(by) {
+        Some(key) => self.consistent_hash(key),
+        None => self.round_robin(),
+      },
+    }
+  }
+
+  /// Selects an upstream for the request according to the balancing strategy.
+  pub(crate) fn select(&self, req: &Request) -> Option<usize> {
+    self.select_by(|by| match by {
+      HashKey::ClientIp => client_ip(req).map(|ip| hash_of(&ip)),
+      HashKey::Cookie(name) => cookie_value(req, name).map(hash_of),
+    })
+  }
+
+  /// Selects an upstream for a stream connection of the client; cookies can't be hashed, so they fall back to
+  /// the round robin.
+  pub(crate) fn select_for_peer(&self, peer: IpAddr) -> Option<usize> {
+    self.select_by(|by| matches!(by, HashKey::ClientIp).then(|| hash_of(&peer)))
+  }
+
+  /// Selects an upstream, or any of them if none is available.
+  pub(crate) fn elect(&self, req: &Request) -> Option<usize> {
+    // If nothing is available, the client answers with 503 by itself, so any upstream can be elected here.
+    self.select(req).or_else(|| (!self.nodes.is_empty()).then_some(0))
+  }
+
+  /// Returns the next available upstream after the given one, or the same one if no other is available.
+  fn next_after(&self, idx: usize) -> usize {
+    self.first_available_from((idx + 1) % self.nodes.len()).unwrap_or(idx)
+  }
+
+  /// Finds the upstream the proxied request is addressed to.
+  pub(crate) fn node_for_uri(&self, uri: &str) -> Option<usize> {
+    self
+      .nodes
+      .iter()
+      .enumerate()
+      .filter(|(_, n)| uri.starts_with(n.target.trim_end_matches('/')))
+      .max_by_key(|(_, n)| n.target.trim_end_matches('/').len())
+      .map(|(idx, _)| idx)
+  }
+
+  pub(crate) fn track_connection(self: &Arc<Self>, idx: usize) -> ConnectionGuard {
+    self.nodes[idx].active_connections.fetch_add(1, Ordering::Relaxed);
+    ConnectionGuard { pool: self.clone(), idx }
+  }
+}
+
+impl Drop for ConnectionGuard {
+  fn drop(&mut self) {
+    self.pool.nodes[self.idx]
+      .active_connections
+      .fetch_sub(1, Ordering::Relaxed);
+  }
+}
+
+impl Balancer {
+  pub(crate) fn new(pool: Arc<UpstreamPool>) -> Self {
+    Self { pool }
+  }
+}
+
+impl Upstreams for Balancer {
+  type Error = ServerError;
+
+  async fn elect(&self, req: &Request, _depot: &Depot) -> Result<&str, Self::Error> {
+    self
+      .pool
+      .elect(req)
+      .map(|idx| self.pool.nodes[idx].target.as_str())
+      .ok_or_else(|| ServerError::from_private_str("No upstream to elect!").with_500())
+  }
+}
+
 #[allow(dead_code)]
 impl ModifiedReqwestClient {
   /// Create a new `ModifiedReqwestClient` with the given [`reqwest::Client`].
-  pub fn new(inner: ReqwestCli, server_domain: &str) -> Self {
+  pub fn new(inner: impl Into<Arc<ReqwestCli>>, server_domain: &str) -> Self {
     Self {
-      inner,
+      inner: inner.into(),
       domain: server_domain.to_owned(),
+      pool: None,
+      policy: Default::default(),
+      proxy_header: None,
+      node: None,
+      sockets: None,
     }
   }
 
-  pub fn new_client<U: Upstreams>(upstreams: U, server_domain: &str) -> Proxy<U, ModifiedReqwestClient> {
-    Proxy::new(
-      upstreams,
-      ModifiedReqwestClient::new(
-        ReqwestCli::builder()
-          .redirect(reqwest::redirect::Policy::none())
-          .build()
-          .unwrap(),
-        server_domain,
-      ),
-    )
+  pub fn new_client(
+    inner: Arc<ReqwestCli>,
+    sockets: Arc<SocketConnections>,
+    pool: Arc<UpstreamPool>,
+    policy: Arc<RequestPolicy>,
+    server_domain: &str,
+  ) -> Proxy<Balancer, ModifiedReqwestClient> {
+    let client = ModifiedReqwestClient::new(inner, server_domain)
+      .with_pool(pool.clone())
+      .with_policy(policy)
+      .with_sockets(sockets);
+    Proxy::new(Balancer::new(pool), client)
+  }
+
+  pub fn with_policy(mut self, policy: Arc<RequestPolicy>) -> Self {
+    self.policy = policy;
+    self
+  }
+
+  /// Tracks connections and failures of upstreams from the pool.
+  pub fn with_pool(mut self, pool: Arc<UpstreamPool>) -> Self {
+    self.pool = Some(pool);
+    self
+  }
+
+  /// Sends the PROXY protocol header before requests; they are sent over a new connection each.
+  pub fn with_proxy_header(mut self, header: Vec<u8>) -> Self {
+    self.proxy_header = Some(header);
+    self
+  }
+
+  /// Keeps connections to `unix:` upstreams in the given pool instead of opening one per request.
+  pub fn with_sockets(mut self, sockets: Arc<SocketConnections>) -> Self {
+    self.sockets = Some(sockets);
+    self
+  }
+
+  /// Proxies to the upstream of the pool elected beforehand instead of finding it by the proxied URI.
+  pub fn with_node(mut self, idx: usize, captures: Vec<(String, String)>) -> Self {
+    self.node = Some((idx, captures));
+    self
   }
 
   #[allow(clippy::wrong_self_convention)]
@@ -74,6 +551,245 @@ impl ModifiedReqwestClient {
   }
 }
 
+/// Settings and upstreams a shared client is built for.
+#[derive(PartialEq, Eq, Hash)]
+struct ClientKey {
+  upstreams: Vec<String>,
+  connect_timeout: Option<u64>,
+  pool: ConnectionPool,
+}
+
+/// Clients alive in the current or previous config, so unchanged services keep their connections on reload.
+static UPSTREAM_CLIENTS: LazyLock<Mutex<HashMap<ClientKey, Weak<ReqwestCli>>>> = LazyLock::new(Default::default);
+
+/// Connections to `unix:` upstreams, shared and kept on reload like [`UPSTREAM_CLIENTS`].
+static UPSTREAM_SOCKETS: LazyLock<Mutex<HashMap<ClientKey, Weak<SocketConnections>>>> =
+  LazyLock::new(Default::default);
+
+/// Upstreams and settings a shared pool is built for; upstreams keep their order, which indexes their state.
+#[derive(PartialEq, Eq, Hash)]
+struct PoolKey {
+  upstreams: Vec<(String, u32)>,
+  working_dir: Option<PathBuf>,
+  strategy: BalancingStrategy,
+  breaker: Option<CircuitBreaker>,
+  health_check: Option<HealthCheck>,
+}
+
+/// Pools of the current or previous config, so unchanged services keep their balancing, circuit breaker and health
+/// state on reload.
+static UPSTREAM_POOLS: LazyLock<Mutex<HashMap<PoolKey, Weak<UpstreamPool>>>> = LazyLock::new(Default::default);
+
+/// Returns the value shared under the `key`, building it if nobody holds it anymore.
+fn shared<K: Eq + Hash, T>(values: &Mutex<HashMap<K, Weak<T>>>, key: K, build: impl FnOnce(&K) -> T) -> Arc<T> {
+  let mut values = values.lock().unwrap();
+  values.retain(|_, value| value.strong_count() > 0);
+  if let Some(value) = values.get(&key).and_then(Weak::upgrade) {
+    return value;
+  }
+  let value = Arc::new(build(&key));
+  values.insert(key, Arc::downgrade(&value));
+  value
+}
+
+fn client_key(service: &CommonService) -> ClientKey {
+  let mut upstreams = service.to.iter().map(|u| u.url().to_owned()).collect::<Vec<_>>();
+  upstreams.sort();
+  ClientKey {
+    upstreams,
+    connect_timeout: service.connect_timeout,
+    pool: service.connection_pool.clone().unwrap_or_default(),
+  }
+}
+
+fn build_upstream_client(key: &ClientKey) -> ReqwestCli {
+  let mut builder = ReqwestCli::builder()
+    .redirect(reqwest::redirect::Policy::none())
+    .pool_idle_timeout(Duration::from_secs(key.pool.idle_keep_alive.unwrap_or(90)))
+    .tcp_nodelay(key.pool.tcp_nodelay.unwrap_or(true))
+    .tcp_keepalive(key.pool.tcp_keepalive.map(Duration::from_secs));
+  if let Some(connect_timeout) = key.connect_timeout {
+    builder = builder.connect_timeout(Duration::from_secs(connect_timeout));
+  }
+  if let Some(max_idle) = key.pool.max_idle_per_host {
+    builder = builder.pool_max_idle_per_host(max_idle);
+  }
+  if key.pool.http2_prior_knowledge.is_some_and(|v| v) {
+    builder = builder.http2_prior_knowledge();
+  }
+  builder.build().unwrap()
+}
+
+/// Returns the client for upstreams of the service, shared with services of the same upstreams and settings.
+pub(crate) fn upstream_client(service: &CommonService) -> Arc<ReqwestCli> {
+  shared(&UPSTREAM_CLIENTS, client_key(service), build_upstream_client)
+}
+
+/// Returns the pool of connections to `unix:` upstreams of the service, shared like [`upstream_client`].
+pub(crate) fn upstream_sockets(service: &CommonService) -> Arc<SocketConnections> {
+  shared(&UPSTREAM_SOCKETS, client_key(service), |key| SocketConnections::new(&key.pool))
+}
+
+/// Returns the pool of upstreams of the service, shared like [`upstream_client`] while upstreams and their
+/// balancing, circuit breaker and health check settings are the same.
+pub(crate) fn upstream_pool(service: &CommonService) -> Arc<UpstreamPool> {
+  let key = PoolKey {
+    upstreams: service.to.iter().map(|u| (u.url().to_owned(), u.weight())).collect(),
+    working_dir: service.working_dir.clone(),
+    strategy: service.balancing.clone().unwrap_or_default(),
+    breaker: service.circuit_breaker.clone(),
+    health_check: service.health_check.clone(),
+  };
+  shared(&UPSTREAM_POOLS, key, |key| {
+    UpstreamPool::new(
+      &service.to,
+      key.working_dir.as_deref(),
+      key.strategy.clone(),
+      key.breaker.clone(),
+    )
+  })
+}
+
+type SocketSender = hyper::client::conn::http1::SendRequest<StreamBody<RequestFrames>>;
+
+/// Idle HTTP/1.1 connections to upstreams listening on unix sockets.
+#[derive(Debug)]
+pub(crate) struct SocketConnections {
+  max_idle: Option<usize>,
+  idle_keep_alive: Duration,
+  idle: Mutex<HashMap<PathBuf, Vec<(SocketSender, Instant)>>>,
+}
+
+impl SocketConnections {
+  fn new(pool: &ConnectionPool) -> Self {
+    Self {
+      max_idle: pool.max_idle_per_host,
+      idle_keep_alive: Duration::from_secs(pool.idle_keep_alive.unwrap_or(90)),
+      idle: Default::default(),
+    }
+  }
+
+  fn is_alive(&self, sender: &SocketSender, since: Instant) -> bool {
+    !sender.is_closed() && since.elapsed() < self.idle_keep_alive
+  }
+
+  /// Takes an idle connection to the socket, dropping expired ones on the way.
+  fn take(&self, socket: &Path) -> Option<SocketSender> {
+    let mut idle = self.idle.lock().unwrap();
+    let senders = idle.get_mut(socket)?;
+    while let Some((sender, since)) = senders.pop() {
+      if self.is_alive(&sender, since) && sender.is_ready() {
+        return Some(sender);
+      }
+    }
+    None
+  }
+
+  /// Returns the connection to the pool once its response is read; upgraded and closed connections are dropped.
+  fn release(self: &Arc<Self>, socket: &Path, mut sender: SocketSender) {
+    let connections = self.clone();
+    let socket = socket.to_path_buf();
+    tokio::spawn(async move {
+      if sender.ready().await.is_err() {
+        return;
+      }
+      let mut idle = connections.idle.lock().unwrap();
+      let senders = idle.entry(socket).or_default();
+      senders.retain(|(sender, since)| connections.is_alive(sender, *since));
+      if connections.max_idle.is_none_or(|max| senders.len() < max) {
+        senders.push((sender, Instant::now()));
+      }
+    });
+  }
+}
+
+/// Proxies requests of a service electing the upstream per request.
+///
+/// Used for host patterns, whose captures are substituted into the upstream URL with the requested host kept,
+/// and for services sending the PROXY protocol header.
+pub(crate) struct ServiceProxy {
+  pattern: Arc<HostPattern>,
+  pool: Arc<UpstreamPool>,
+  matcher: Arc<PathMatcher>,
+  domain: Option<String>,
+  proxy_protocol: Option<ProxyProtocolVersion>,
+  policy: Arc<RequestPolicy>,
+  client: Arc<ReqwestCli>,
+  sockets: Arc<SocketConnections>,
+}
+
+impl ServiceProxy {
+  pub(crate) fn new(
+    service: &CommonService,
+    pattern: Arc<HostPattern>,
+    pool: Arc<UpstreamPool>,
+    matcher: Arc<PathMatcher>,
+  ) -> Self {
+    let policy = Arc::new(RequestPolicy::new(service));
+    Self {
+      domain: matches!(pattern.as_ref(), HostPattern::Exact(_)).then(|| service.from.clone()),
+      pattern,
+      pool,
+      matcher,
+      proxy_protocol: service.send_proxy_protocol.clone(),
+      client: upstream_client(service),
+      sockets: upstream_sockets(service),
+      policy,
+    }
+  }
+}
+
+#[impulse_server_kit::salvo::async_trait]
+impl impulse_server_kit::salvo::Handler for ServiceProxy {
+  #[tracing::instrument(
+    skip_all,
+    name = "service-proxy",
+    fields(
+      http.uri = req.uri().path(),
+      http.method = req.method().as_str()
+    )
+  )]
+  async fn handle(&self, req: &mut Request, depot: &mut Depot, res: &mut Response, ctrl: &mut salvo::FlowCtrl) {
+    let host = request_host(req);
+    let captures = self.pattern.captures(&host).unwrap_or_default();
+    let Some(idx) = self.pool.elect(req) else {
+      ServerError::from_private_str("No upstream to elect!")
+        .with_500()
+        .write(req, depot, res)
+        .await;
+      return;
+    };
+    let upstream = fill_placeholders(&self.pool.nodes[idx].target, &captures);
+
+    let domain = match &self.domain {
+      Some(domain) => domain.clone(),
+      None => req
+        .headers()
+        .get(salvo::http::header::HOST)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or(host.as_str())
+        .to_owned(),
+    };
+    let mut client = ModifiedReqwestClient::new(self.client.clone(), &domain)
+      .with_pool(self.pool.clone())
+      .with_policy(self.policy.clone())
+      .with_sockets(self.sockets.clone())
+      .with_node(idx, captures);
+    if let Some(version) = &self.proxy_protocol
+      && let Some(src) = client_addr(req)
+      && let Some(dst) = req.local_addr().clone().into_std()
+    {
+      client = client.with_proxy_header(encode_header(version, src, dst));
+    }
+    let matcher = self.matcher.clone();
+    client
+      .as_client(upstream)
+      .with_url_path_getter(move |req: &Request, _: &Depot| matcher.forwarded_path(req))
+      .handle(req, depot, res, ctrl)
+      .await;
+  }
+}
+
 type HyperRequest = hyper::Request<ReqBody>;
 type HyperResponse = hyper::Response<ResBody>;
 
@@ -97,6 +813,401 @@ fn get_upgrade_type(headers: &HeaderMap) -> Option<&str> {
   None
 }
 
+trait UpgradedIo: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> UpgradedIo for T {}
+
+/// Response of an upstream, received with `reqwest` or over a dedicated HTTP/1.1 connection.
+enum UpstreamResponse {
+  Reqwest(reqwest::Response),
+  Hyper(hyper::Response<hyper::body::Incoming>),
+}
+
+impl UpstreamResponse {
+  fn status(&self) -> StatusCode {
+    match self {
+      Self::Reqwest(response) => response.status(),
+      Self::Hyper(response) => response.status(),
+    }
+  }
+
+  fn version(&self) -> hyper::Version {
+    match self {
+      Self::Reqwest(response) => response.version(),
+      Self::Hyper(response) => response.version(),
+    }
+  }
+
+  fn headers(&self) -> &HeaderMap {
+    match self {
+      Self::Reqwest(response) => response.headers(),
+      Self::Hyper(response) => response.headers(),
+    }
+  }
+
+  async fn upgrade(self) -> Result<Box<dyn UpgradedIo>, String> {
+    let upgraded: Box<dyn UpgradedIo> = match self {
+      Self::Reqwest(response) => Box::new(response.upgrade().await.map_err(|e| e.to_string())?),
+      Self::Hyper(mut response) => Box::new(TokioIo::new(
+        hyper::upgrade::on(&mut response).await.map_err(|e| e.to_string())?,
+      )),
+    };
+    Ok(upgraded)
+  }
+
+  /// Streams the body keeping the upstream connection tracked until it is sent.
+  fn into_body(self, connection_guard: Option<ConnectionGuard>, idle_timeout: Option<Duration>) -> ResBody {
+    let chunks: BoxStream<'static, std::io::Result<Bytes>> = match self {
+      Self::Reqwest(response) => response.bytes_stream().map_err(std::io::Error::other).boxed(),
+      Self::Hyper(response) => BodyDataStream::new(response.into_body())
+        .map_err(std::io::Error::other)
+        .boxed(),
+    };
+    let chunks = match idle_timeout {
+      Some(idle_timeout) => futures_util::stream::unfold(Some(chunks), move |chunks| async move {
+        let mut chunks = chunks?;
+        match tokio::time::timeout(idle_timeout, chunks.next()).await {
+          Ok(chunk) => chunk.map(|chunk| (chunk, Some(chunks))),
+          Err(_) => Some((
+            Err(std::io::Error::new(
+              std::io::ErrorKind::TimedOut,
+              "Upstream is idle for too long",
+            )),
+            None,
+          )),
+        }
+      })
+      .boxed(),
+      None => chunks,
+    };
+    ResBody::stream(chunks.map_ok(move |chunk| {
+      let _guard = &connection_guard;
+      chunk
+    }))
+  }
+}
+
+/// Failure of a single attempt to get a response from an upstream.
+enum UpstreamFailure {
+  /// Connection refused, DNS resolution or protocol errors.
+  Connect(String),
+  Timeout,
+  /// The request body exceeds `max_body_size`.
+  BodyTooLarge,
+  /// The request can't be sent at all; not the upstream fault.
+  Internal(ServerError),
+}
+
+impl UpstreamFailure {
+  fn from_reqwest(e: reqwest::Error) -> Self {
+    if e.is_timeout() {
+      Self::Timeout
+    } else {
+      Self::Connect(e.to_string())
+    }
+  }
+
+  /// Whether the failure is caused by the upstream.
+  fn is_upstream_fault(&self) -> bool {
+    matches!(self, Self::Connect(_) | Self::Timeout)
+  }
+
+  fn into_response(self) -> Result<HyperResponse, ServerError> {
+    let error = match self {
+      Self::Connect(reason) => {
+        tracing::warn!(reason, "Upstream is unreachable");
+        GatewayError::Unreachable
+      }
+      Self::Timeout => {
+        tracing::warn!("Upstream hasn't responded in time");
+        GatewayError::Timeout
+      }
+      Self::BodyTooLarge => GatewayError::BodyTooLarge,
+      Self::Internal(e) => return Err(e),
+    };
+    gateway_error(error)
+  }
+}
+
+/// Request body frames as they are sent to an upstream.
+type RequestFrames = BoxStream<'static, std::io::Result<Frame<Bytes>>>;
+
+/// Fails the body once it exceeds the limit; `exceeded` tells this failure from errors of the client.
+fn limited_body(body: ReqBody, limit: Option<u64>, exceeded: Arc<AtomicBool>) -> RequestFrames {
+  let mut size = 0u64;
+  body
+    .map(move |frame| {
+      let frame = frame.map_err(std::io::Error::other)?;
+      size += frame.data_ref().map(|data| data.len() as u64).unwrap_or_default();
+      if limit.is_some_and(|limit| size > limit) {
+        exceeded.store(true, Ordering::Relaxed);
+        return Err(std::io::Error::other("Request body is too large"));
+      }
+      Ok(frame)
+    })
+    .boxed()
+}
+
+/// Runs the future within the timeout, if any; `None` means the timeout is exceeded.
+async fn within<F: Future>(timeout: Option<Duration>, future: F) -> Option<F::Output> {
+  match timeout {
+    Some(timeout) => tokio::time::timeout(timeout, future).await.ok(),
+    None => Some(future.await),
+  }
+}
+
+/// Request with its body kept to be sent again on retries.
+struct RetriedRequest {
+  parts: hyper::http::request::Parts,
+  uri: String,
+  body: Bytes,
+}
+
+impl RetriedRequest {
+  async fn new(request: HyperRequest, max_body_size: Option<u64>) -> Result<Self, UpstreamFailure> {
+    let (parts, body) = request.into_parts();
+    let exceeded = Arc::new(AtomicBool::new(false));
+    let body = limited_body(body, max_body_size, exceeded.clone())
+      .try_fold(Vec::new(), |mut body, frame| async move {
+        if let Ok(data) = frame.into_data() {
+          body.extend_from_slice(&data);
+        }
+        Ok(body)
+      })
+      .await
+      .map_err(|e| {
+        if exceeded.load(Ordering::Relaxed) {
+          UpstreamFailure::BodyTooLarge
+        } else {
+          UpstreamFailure::Internal(
+            ServerError::from_private(e)
+              .with_public("Can't read request body!")
+              .with_500(),
+          )
+        }
+      })?;
+    Ok(Self {
+      uri: parts.uri.to_string(),
+      parts,
+      body: body.into(),
+    })
+  }
+
+  fn build(&self) -> Result<HyperRequest, UpstreamFailure> {
+    let mut request = hyper::Request::new(ReqBody::Once(self.body.clone()));
+    *request.method_mut() = self.parts.method.clone();
+    *request.version_mut() = self.parts.version;
+    *request.headers_mut() = self.parts.headers.clone();
+    *request.uri_mut() = self.uri.parse().map_err(|e| {
+      UpstreamFailure::Internal(
+        ServerError::from_private(e)
+          .with_public("Can't convert proxied request!")
+          .with_500(),
+      )
+    })?;
+    Ok(request)
+  }
+
+  /// Addresses the request to another upstream.
+  fn retarget(&mut self, from: &str, to: &str) {
+    if let Some(rest) = self.uri.strip_prefix(from.trim_end_matches('/')) {
+      self.uri = format!("{}{rest}", to.trim_end_matches('/'));
+    }
+  }
+}
+
+async fn copy_upgraded(mut response_upgraded: Box<dyn UpgradedIo>, request_upgraded: OnUpgrade) {
+  match request_upgraded.await {
+    Ok(request_upgraded) => {
+      let mut request_upgraded = TokioIo::new(request_upgraded);
+      if let Err(e) = copy_bidirectional_with_sizes(
+        &mut response_upgraded,
+        &mut request_upgraded,
+        COPY_BIDIRECTIONAL_BUF_SIZE,
+        COPY_BIDIRECTIONAL_BUF_SIZE,
+      )
+      .await
+      {
+        tracing::error!(error = ?e, "copying between upgraded connections failed");
+      }
+    }
+    Err(e) => tracing::error!(error = ?e, "upgrade request failed"),
+  }
+}
+
+/// Sends `GET` request to the upstream listening on the unix socket; returns the response status.
+pub(crate) async fn socket_get(socket: &Path, path: &str) -> std::io::Result<StatusCode> {
+  let stream = UnixStream::connect(socket).await?;
+  let (mut sender, connection) = hyper::client::conn::http1::handshake(TokioIo::new(stream))
+    .await
+    .map_err(std::io::Error::other)?;
+  tokio::spawn(connection);
+  let request = hyper::Request::get(path)
+    .header(hyper::header::HOST, "localhost")
+    .body(Empty::<Bytes>::new())
+    .map_err(std::io::Error::other)?;
+  sender
+    .send_request(request)
+    .await
+    .map(|response| response.status())
+    .map_err(std::io::Error::other)
+}
+
+impl ModifiedReqwestClient {
+  /// Opens a new connection to the upstream: the unix `socket` or the host of the URI.
+  async fn connect(&self, uri: &hyper::Uri, socket: Option<&Path>) -> Result<Box<dyn UpgradedIo>, UpstreamFailure> {
+    let connected: std::io::Result<Box<dyn UpgradedIo>> = match socket {
+      Some(socket) => within(self.policy.connect_timeout, UnixStream::connect(socket))
+        .await
+        .ok_or(UpstreamFailure::Timeout)?
+        .map(|stream| Box::new(stream) as Box<dyn UpgradedIo>),
+      None => {
+        let Some(host) = uri.host() else {
+          return Err(UpstreamFailure::Internal(
+            ServerError::from_private_str("Upstream URL has no host!").with_500(),
+          ));
+        };
+        let connect = TcpStream::connect(format!("{host}:{}", uri.port_u16().unwrap_or(80)));
+        within(self.policy.connect_timeout, connect)
+          .await
+          .ok_or(UpstreamFailure::Timeout)?
+          .map(|stream| Box::new(stream) as Box<dyn UpgradedIo>)
+      }
+    };
+    connected.map_err(|e| UpstreamFailure::Connect(e.to_string()))
+  }
+
+  /// Opens a new HTTP/1.1 connection, optionally starting with the PROXY protocol header.
+  async fn handshake(
+    &self,
+    uri: &hyper::Uri,
+    socket: Option<&Path>,
+    header: Option<&[u8]>,
+  ) -> Result<SocketSender, UpstreamFailure> {
+    let mut stream = self.connect(uri, socket).await?;
+    if let Some(header) = header {
+      stream
+        .write_all(header)
+        .await
+        .map_err(|e| UpstreamFailure::Connect(e.to_string()))?;
+    }
+
+    let (sender, connection) = hyper::client::conn::http1::handshake(TokioIo::new(stream))
+      .await
+      .map_err(|e| UpstreamFailure::Connect(e.to_string()))?;
+    tokio::spawn(async move {
+      if let Err(e) = connection.with_upgrades().await {
+        tracing::debug!(error = ?e, "Upstream connection is closed");
+      }
+    });
+    Ok(sender)
+  }
+
+  /// Sends the request over the HTTP/1.1 connection.
+  async fn send_http1(
+    &self,
+    sender: &mut SocketSender,
+    mut proxied_request: hyper::Request<RequestFrames>,
+  ) -> Result<UpstreamResponse, UpstreamFailure> {
+    *proxied_request.uri_mut() = proxied_request
+      .uri()
+      .path_and_query()
+      .map(|path| path.as_str())
+      .unwrap_or("/")
+      .parse()
+      .map_err(|e| {
+        UpstreamFailure::Internal(
+          ServerError::from_private(e)
+            .with_public("Can't convert proxied request!")
+            .with_500(),
+        )
+      })?;
+    *proxied_request.version_mut() = hyper::Version::HTTP_11;
+    within(
+      self.policy.request_timeout,
+      sender
+        .send_request(proxied_request.map(StreamBody::new))
+        .instrument(tracing::debug_span!("hyper::send_request")),
+    )
+    .await
+    .ok_or(UpstreamFailure::Timeout)?
+    .map(UpstreamResponse::Hyper)
+    .map_err(|e| UpstreamFailure::Connect(e.to_string()))
+  }
+
+  /// Sends the request over a new HTTP/1.1 connection, optionally starting with the PROXY protocol header.
+  async fn send_over_connection(
+    &self,
+    proxied_request: hyper::Request<RequestFrames>,
+    socket: Option<&Path>,
+    header: Option<&[u8]>,
+  ) -> Result<UpstreamResponse, UpstreamFailure> {
+    let mut sender = self.handshake(proxied_request.uri(), socket, header).await?;
+    self.send_http1(&mut sender, proxied_request).await
+  }
+
+  /// Sends the request over an idle connection to the socket or a new one, which is pooled afterwards.
+  async fn send_over_socket(
+    &self,
+    proxied_request: hyper::Request<RequestFrames>,
+    sockets: &Arc<SocketConnections>,
+    socket: &Path,
+  ) -> Result<UpstreamResponse, UpstreamFailure> {
+    let mut sender = match sockets.take(socket) {
+      Some(sender) => sender,
+      None => self.handshake(proxied_request.uri(), Some(socket), None).await?,
+    };
+    let outcome = self.send_http1(&mut sender, proxied_request).await;
+    sockets.release(socket, sender);
+    outcome
+  }
+
+  async fn send(&self, proxied_request: hyper::Request<RequestFrames>) -> Result<UpstreamResponse, UpstreamFailure> {
+    let proxied_request =
+      proxied_request.map(|s| reqwest::Body::wrap_stream(s.map_ok(|s| s.into_data().unwrap_or_default())));
+    let proxied_request: reqwest::Request = proxied_request.try_into().map_err(|e| {
+      UpstreamFailure::Internal(
+        ServerError::from_private(e)
+          .with_public("Can't convert proxied request!")
+          .with_500(),
+      )
+    })?;
+    within(
+      self.policy.request_timeout,
+      self
+        .inner
+        .execute(proxied_request)
+        .instrument(tracing::debug_span!("reqwest::execute")),
+    )
+    .await
+    .ok_or(UpstreamFailure::Timeout)?
+    .map(UpstreamResponse::Reqwest)
+    .map_err(UpstreamFailure::from_reqwest)
+  }
+
+  async fn send_once(
+    &self,
+    proxied_request: HyperRequest,
+    socket: Option<&Path>,
+  ) -> Result<UpstreamResponse, UpstreamFailure> {
+    let exceeded = Arc::new(AtomicBool::new(false));
+    let proxied_request = proxied_request.map(|body| limited_body(body, self.policy.max_body_size, exceeded.clone()));
+    let outcome = match (socket, &self.proxy_header, &self.sockets) {
+      (None, None, _) => self.send(proxied_request).await,
+      (Some(socket), None, Some(sockets)) => self.send_over_socket(proxied_request, sockets, socket).await,
+      (socket, header, _) => {
+        self
+          .send_over_connection(proxied_request, socket, header.as_deref())
+          .await
+      }
+    };
+    match outcome {
+      Err(_) if exceeded.load(Ordering::Relaxed) => Err(UpstreamFailure::BodyTooLarge),
+      outcome => outcome,
+    }
+  }
+}
+
 impl ProxyCli for ModifiedReqwestClient {
   type Error = ServerError;
 
@@ -131,24 +1242,122 @@ impl ProxyCli for ModifiedReqwestClient {
 
     let request_upgrade_type = get_upgrade_type(proxied_request.headers()).map(|s| s.to_owned());
 
-    let proxied_request =
-      proxied_request.map(|s| reqwest::Body::wrap_stream(s.map_ok(|s| s.into_data().unwrap_or_default())));
-    let response = self
-      .inner
-      .execute(proxied_request.try_into().map_err(|e| {
-        ServerError::from_private(e)
-          .with_public("Can't convert proxied request!")
-          .with_500()
-      })?)
-      .instrument(tracing::debug_span!("reqwest::execute"))
-      .await
-      .map_err(|e| {
-        ServerError::from_private(e)
-          .with_public("Can't execute request!")
-          .with_404()
-      })?;
+    if let Some(pool) = &self.pool
+      && let Some(retry_after) = pool.unavailable_for()
+    {
+      tracing::warn!("Every upstream is unavailable");
+      return service_unavailable(retry_after);
+    }
+    if let Some(limit) = self.policy.max_body_size
+      && proxied_request
+        .headers()
+        .get(hyper::header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok())
+        .is_some_and(|len| len > limit)
+    {
+      return gateway_error(GatewayError::BodyTooLarge);
+    }
 
-    let res_headers = response.headers().clone();
+    // Exact hosts are balanced by `Balancer`, whose URLs have no placeholders and are found by the prefix.
+    let mut upstream = self.pool.as_ref().and_then(|pool| {
+      match &self.node {
+        Some((idx, _)) => Some(*idx),
+        None => pool.node_for_uri(&proxied_request.uri().to_string()),
+      }
+      .map(|idx| (pool.clone(), idx))
+    });
+
+    // Upgraded connections can't be replayed; bodies of retried requests are kept in memory, so they must be limited
+    // by `max_body_size` or be small and of known size.
+    let body_fits = self.policy.max_body_size.is_some()
+      || proxied_request
+        .body()
+        .size_hint()
+        .exact()
+        .is_some_and(|size| size <= DEFAULT_RETRY_BODY_SIZE);
+    let retry = self.policy.retry.as_ref().filter(|retry| {
+      request_upgrade_type.is_none() && body_fits && retry.allows_method(proxied_request.method().as_str())
+    });
+    let (mut proxied_request, mut retried) = match retry {
+      Some(_) => match RetriedRequest::new(proxied_request, self.policy.max_body_size).await {
+        Ok(retried) => (None, Some(retried)),
+        Err(failure) => return failure.into_response(),
+      },
+      None => (Some(proxied_request), None),
+    };
+
+    let mut attempt = 0;
+    let (response, connection_guard) = loop {
+      let request = match (&retried, proxied_request.take()) {
+        (Some(retried), _) => retried.build(),
+        (None, Some(request)) => Ok(request),
+        (None, None) => Err(UpstreamFailure::Internal(
+          ServerError::from_private_str("Proxied request is already sent!").with_500(),
+        )),
+      };
+      if let Some((pool, idx)) = &upstream {
+        pool.breaker_acquire(*idx);
+      }
+      let connection_guard = upstream.as_ref().map(|(pool, idx)| pool.track_connection(*idx));
+      let outcome = match request {
+        Ok(request) => {
+          let socket = upstream
+            .as_ref()
+            .and_then(|(pool, idx)| pool.nodes[*idx].socket.as_deref());
+          self.send_once(request, socket).await
+        }
+        Err(e) => Err(e),
+      };
+
+      if let Some((pool, idx)) = &upstream {
+        match &outcome {
+          Ok(response) if !response.status().is_server_error() => pool.report_success(*idx),
+          Err(failure) if !failure.is_upstream_fault() => {}
+          _ => pool.report_failure(*idx),
+        }
+      }
+
+      let retryable = retry.is_some_and(|retry| match &outcome {
+        Ok(response) => retry.retries_status(response.status().as_u16()),
+        Err(UpstreamFailure::Connect(_)) => retry.retries_error(RetryOn::Connect),
+        Err(UpstreamFailure::Timeout) => retry.retries_error(RetryOn::Timeout),
+        Err(UpstreamFailure::BodyTooLarge | UpstreamFailure::Internal(_)) => false,
+      });
+      match (retry, &mut retried) {
+        (Some(retry), Some(retried)) if retryable && attempt < retry.attempts() => {
+          attempt += 1;
+          tracing::warn!(attempt, "Retrying the request");
+          drop(connection_guard);
+          tokio::time::sleep(retry.backoff(attempt)).await;
+          if retry.next_upstream()
+            && let Some((pool, idx)) = &mut upstream
+          {
+            let next = pool.next_after(*idx);
+            let captures = self
+              .node
+              .as_ref()
+              .map(|(_, captures)| captures.as_slice())
+              .unwrap_or_default();
+            retried.retarget(
+              &fill_placeholders(&pool.nodes[*idx].target, captures),
+              &fill_placeholders(&pool.nodes[next].target, captures),
+            );
+            *idx = next;
+          }
+        }
+        _ => break (outcome, connection_guard),
+      }
+    };
+
+    let response = match response {
+      Ok(response) => response,
+      Err(failure) => return failure.into_response(),
+    };
+
+    let mut res_headers = response.headers().clone();
+    // Only `lbrp` itself may tell its failures apart from upstream errors.
+    res_headers.remove(GATEWAY_ERROR_HEADER);
 
     let hyper_response = hyper::Response::builder()
       .status(response.status())
@@ -157,38 +1366,24 @@ impl ProxyCli for ModifiedReqwestClient {
     let mut hyper_response = if response.status() == StatusCode::SWITCHING_PROTOCOLS {
       let response_upgrade_type = get_upgrade_type(response.headers());
 
-      if request_upgrade_type == response_upgrade_type.map(|s| s.to_lowercase()) {
-        let mut response_upgraded = response.upgrade().await.map_err(|e| {
-          ServerError::from_private(e)
-            .with_public("Can't upgrade response!")
-            .with_500()
-        })?;
-        if let Some(request_upgraded) = request_upgraded {
-          tokio::spawn(async move {
-            match request_upgraded.await {
-              Ok(request_upgraded) => {
-                let mut request_upgraded = TokioIo::new(request_upgraded);
-                if let Err(e) = copy_bidirectional_with_sizes(
-                  &mut response_upgraded,
-                  &mut request_upgraded,
-                  COPY_BIDIRECTIONAL_BUF_SIZE,
-                  COPY_BIDIRECTIONAL_BUF_SIZE,
-                )
-                .await
-                {
-                  tracing::error!(error = ?e, "copying between upgraded connections failed");
-                }
-              }
-              Err(e) => tracing::error!(error = ?e, "upgrade request failed"),
-            }
-          });
-        } else {
-          ServerError::from_private_str("request does not have an upgrade extension")
-            .with_500()
-            .bail()?;
+      if request_upgrade_type != response_upgrade_type.map(|s| s.to_lowercase()) {
+        tracing::warn!(?request_upgrade_type, ?response_upgrade_type, "Upgrade type mismatch");
+        return gateway_error(GatewayError::BadResponse);
+      }
+      let response_upgraded = match response.upgrade().await {
+        Ok(response_upgraded) => response_upgraded,
+        Err(reason) => {
+          tracing::warn!(reason, "Can't upgrade the upstream connection");
+          return gateway_error(GatewayError::BadResponse);
         }
+      };
+      if let Some(request_upgraded) = request_upgraded {
+        tokio::spawn(async move {
+          let _connection_guard = connection_guard;
+          copy_upgraded(response_upgraded, request_upgraded).await;
+        });
       } else {
-        ServerError::from_private_str("upgrade type mismatch")
+        ServerError::from_private_str("request does not have an upgrade extension")
           .with_500()
           .bail()?;
       }
@@ -199,7 +1394,7 @@ impl ProxyCli for ModifiedReqwestClient {
       })?
     } else {
       hyper_response
-        .body(ResBody::stream(response.bytes_stream()))
+        .body(response.into_body(connection_guard, self.policy.idle_timeout))
         .map_err(|e| {
           ServerError::from_private(e)
             .with_public("Can't set document body!")
@@ -0,0 +1,289 @@
+use impulse_server_kit::prelude::*;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, LazyLock, RwLock};
+use std::time::Duration;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, BufReader, copy_bidirectional};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::config::{ProxyProtocolListener, ProxyProtocolVersion};
+use crate::header_handling::TrustedProxies;
+
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+/// Longest possible v1 header, including `\r\n`.
+const V1_MAX_LEN: u64 = 107;
+/// Time given to a trusted peer to send the header.
+const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Client addresses of relayed connections, keyed by the relay address seen by `lbrp` listeners.
+static PROXIED_PEERS: LazyLock<RwLock<HashMap<SocketAddr, SocketAddr>>> = LazyLock::new(Default::default);
+
+fn canonical(addr: SocketAddr) -> SocketAddr {
+  SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
+
+/// Returns the client address if the connection is relayed from the PROXY protocol listener.
+fn proxied_peer(remote_addr: SocketAddr) -> Option<SocketAddr> {
+  PROXIED_PEERS.read().unwrap().get(&canonical(remote_addr)).copied()
+}
+
+/// Replaces the relay address of requests with the client address from the PROXY protocol header.
+///
+/// Listeners of `lbrp` are started by the server kit, which can't parse the header in its acceptor, so the header is
+/// read by the relay listener. Installed first on the root router, so every next handler sees the client address.
+pub(crate) struct RestoreClientAddr;
+
+#[impulse_server_kit::salvo::async_trait]
+impl impulse_server_kit::salvo::Handler for RestoreClientAddr {
+  async fn handle(&self, req: &mut Request, depot: &mut Depot, res: &mut Response, ctrl: &mut salvo::FlowCtrl) {
+    if let Some(client) = req.remote_addr().clone().into_std().and_then(proxied_peer) {
+      *req.remote_addr_mut() = client.into();
+    }
+    ctrl.call_next(req, depot, res).await;
+  }
+}
+
+/// Keeps the client address of a relayed connection until dropped.
+struct PeerGuard(SocketAddr);
+
+impl PeerGuard {
+  fn register(relay_addr: SocketAddr, client: SocketAddr) -> Self {
+    let relay_addr = canonical(relay_addr);
+    PROXIED_PEERS.write().unwrap().insert(relay_addr, client);
+    Self(relay_addr)
+  }
+}
+
+impl Drop for PeerGuard {
+  fn drop(&mut self) {
+    PROXIED_PEERS.write().unwrap().remove(&self.0);
+  }
+}
+
+fn invalid(msg: &str) -> std::io::Error {
+  std::io::Error::new(std::io::ErrorKind::InvalidData, msg.to_string())
+}
+
+/// Reads the v1 or v2 header; returns the client address, `None` for `LOCAL` and `UNKNOWN` connections.
+async fn read_header<R: AsyncBufRead + Unpin>(stream: &mut R) -> std::io::Result<Option<SocketAddr>> {
+  match stream.fill_buf().await?.first() {
+    Some(b'P') => {
+      let mut line = vec![];
+      (&mut *stream).take(V1_MAX_LEN).read_until(b'\n', &mut line).await?;
+      let line = std::str::from_utf8(&line)
+        .ok()
+        .and_then(|line| line.strip_suffix("\r\n"))
+        .ok_or_else(|| invalid("Invalid PROXY protocol v1 header"))?;
+      match line.split(' ').collect::<Vec<_>>().as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4" | "TCP6", src, _, src_port, _] => {
+          let ip = src.parse::<IpAddr>().map_err(|_| invalid("Invalid source address"))?;
+          let port = src_port.parse::<u16>().map_err(|_| invalid("Invalid source port"))?;
+          Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(invalid("Invalid PROXY protocol v1 header")),
+      }
+    }
+    Some(b'\r') => {
+      let mut header = [0u8; 16];
+      stream.read_exact(&mut header).await?;
+      if header[..12] != V2_SIGNATURE || header[12] >> 4 != 2 {
+        return Err(invalid("Invalid PROXY protocol v2 header"));
+      }
+      let mut addresses = vec![0u8; u16::from_be_bytes([header[14], header[15]]) as usize];
+      stream.read_exact(&mut addresses).await?;
+      match header[12] & 0x0F {
+        // `LOCAL` command, e.g. health checks of the load balancer.
+        0 => return Ok(None),
+        1 => {}
+        _ => return Err(invalid("Unknown PROXY protocol v2 command")),
+      }
+      match header[13] >> 4 {
+        1 if addresses.len() >= 12 => Ok(Some(SocketAddr::new(
+          Ipv4Addr::from(<[u8; 4]>::try_from(&addresses[..4]).unwrap()).into(),
+          u16::from_be_bytes([addresses[8], addresses[9]]),
+        ))),
+        2 if addresses.len() >= 36 => Ok(Some(SocketAddr::new(
+          Ipv6Addr::from(<[u8; 16]>::try_from(&addresses[..16]).unwrap()).into(),
+          u16::from_be_bytes([addresses[32], addresses[33]]),
+        ))),
+        _ => Ok(None),
+      }
+    }
+    _ => Err(invalid("There is no PROXY protocol header")),
+  }
+}
+
+fn to_ipv6(ip: IpAddr) -> Ipv6Addr {
+  match ip {
+    IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+    IpAddr::V6(ip) => ip,
+  }
+}
+
+/// Encodes the header announcing the `src` client connected to `dst`.
+pub(crate) fn encode_header(version: &ProxyProtocolVersion, src: SocketAddr, dst: SocketAddr) -> Vec<u8> {
+  let (src, dst) = (canonical(src), canonical(dst));
+  match version {
+    ProxyProtocolVersion::V1 => match (src.ip(), dst.ip()) {
+      (IpAddr::V4(src_ip), IpAddr::V4(dst_ip)) => {
+        format!("PROXY TCP4 {src_ip} {dst_ip} {} {}\r\n", src.port(), dst.port())
+      }
+      (src_ip, dst_ip) => format!(
+        "PROXY TCP6 {} {} {} {}\r\n",
+        to_ipv6(src_ip),
+        to_ipv6(dst_ip),
+        src.port(),
+        dst.port()
+      ),
+    }
+    .into_bytes(),
+    ProxyProtocolVersion::V2 => {
+      let mut header = V2_SIGNATURE.to_vec();
+      // Version 2, `PROXY` command.
+      header.push(0x21);
+      match (src.ip(), dst.ip()) {
+        (IpAddr::V4(src_ip), IpAddr::V4(dst_ip)) => {
+          header.push(0x11);
+          header.extend(12u16.to_be_bytes());
+          header.extend(src_ip.octets());
+          header.extend(dst_ip.octets());
+        }
+        (src_ip, dst_ip) => {
+          header.push(0x21);
+          header.extend(36u16.to_be_bytes());
+          header.extend(to_ipv6(src_ip).octets());
+          header.extend(to_ipv6(dst_ip).octets());
+        }
+      }
+      header.extend(src.port().to_be_bytes());
+      header.extend(dst.port().to_be_bytes());
+      header
+    }
+  }
+}
+
+async fn relay(stream: TcpStream, peer: SocketAddr, forward_to: String, trusted: Arc<TrustedProxies>) {
+  let mut stream = BufReader::new(stream);
+  let client = if trusted.contains(peer.ip()) {
+    match tokio::time::timeout(HEADER_TIMEOUT, read_header(&mut stream)).await {
+      Ok(Ok(client)) => client.unwrap_or(peer),
+      Ok(Err(e)) => {
+        tracing::warn!(%peer, error = ?e, "Can't read PROXY protocol header");
+        return;
+      }
+      Err(_) => {
+        tracing::warn!(%peer, "PROXY protocol header is not received in time");
+        return;
+      }
+    }
+  } else {
+    peer
+  };
+
+  let mut upstream = match TcpStream::connect(&forward_to).await {
+    Ok(upstream) => upstream,
+    Err(e) => {
+      tracing::error!(forward_to, error = ?e, "Can't relay PROXY protocol connection");
+      return;
+    }
+  };
+  let _peer = match upstream.local_addr() {
+    Ok(relay_addr) => PeerGuard::register(relay_addr, client),
+    Err(e) => {
+      tracing::error!(error = ?e, "Can't get relay address");
+      return;
+    }
+  };
+  if let Err(e) = copy_bidirectional(&mut stream, &mut upstream).await {
+    tracing::debug!(%client, error = ?e, "Relayed connection is closed");
+  }
+}
+
+/// Accepts connections with PROXY protocol headers and relays them to the `lbrp` listener.
+pub(crate) async fn serve_listener(settings: ProxyProtocolListener) {
+  let trusted = Arc::new(TrustedProxies::new(&settings.trusted));
+  let listener = match TcpListener::bind(&settings.listen).await {
+    Ok(listener) => listener,
+    Err(e) => {
+      tracing::error!(listen = settings.listen, error = ?e, "Can't bind PROXY protocol listener");
+      return;
+    }
+  };
+  tracing::info!(
+    listen = settings.listen,
+    forward_to = settings.forward_to,
+    "PROXY protocol listener is started"
+  );
+
+  loop {
+    match listener.accept().await {
+      Ok((stream, peer)) => {
+        tokio::spawn(relay(stream, peer, settings.forward_to.clone(), trusted.clone()));
+      }
+      Err(e) => tracing::warn!(error = ?e, "Can't accept connection"),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  async fn round_trip(version: ProxyProtocolVersion, src: &str) -> Option<SocketAddr> {
+    let src = src.parse().unwrap();
+    let header = encode_header(&version, src, "10.0.0.1:443".parse().unwrap());
+    read_header(&mut header.as_slice()).await.unwrap()
+  }
+
+  #[tokio::test]
+  async fn v1_round_trip() {
+    assert_eq!(
+      round_trip(ProxyProtocolVersion::V1, "192.0.2.7:51000").await,
+      Some("192.0.2.7:51000".parse().unwrap())
+    );
+    assert_eq!(
+      round_trip(ProxyProtocolVersion::V1, "[2001:db8::7]:51000").await,
+      Some("[2001:db8::7]:51000".parse().unwrap())
+    );
+  }
+
+  #[tokio::test]
+  async fn v2_round_trip() {
+    assert_eq!(
+      round_trip(ProxyProtocolVersion::V2, "192.0.2.7:51000").await,
+      Some("192.0.2.7:51000".parse().unwrap())
+    );
+    assert_eq!(
+      round_trip(ProxyProtocolVersion::V2, "[2001:db8::7]:51000").await,
+      Some("[2001:db8::7]:51000".parse().unwrap())
+    );
+  }
+
+  #[test]
+  fn mapped_ipv4_is_sent_as_ipv4() {
+    let header = encode_header(
+      &ProxyProtocolVersion::V1,
+      "[::ffff:192.0.2.7]:51000".parse().unwrap(),
+      "10.0.0.1:443".parse().unwrap(),
+    );
+    assert!(header.starts_with(b"PROXY TCP4 192.0.2.7 "));
+  }
+
+  #[tokio::test]
+  async fn local_and_unknown_have_no_client() {
+    let mut local = V2_SIGNATURE.to_vec();
+    local.extend([0x20, 0x00, 0x00, 0x00]);
+    assert_eq!(read_header(&mut local.as_slice()).await.unwrap(), None);
+    assert_eq!(read_header(&mut &b"PROXY UNKNOWN\r\n"[..]).await.unwrap(), None);
+  }
+
+  #[tokio::test]
+  async fn rejects_invalid_headers() {
+    let mut unknown_command = V2_SIGNATURE.to_vec();
+    unknown_command.extend([0x22, 0x00, 0x00, 0x00]);
+    assert!(read_header(&mut unknown_command.as_slice()).await.is_err());
+    assert!(read_header(&mut &b"PROXY TCP4 nope 10.0.0.1 1 2\r\n"[..]).await.is_err());
+    assert!(read_header(&mut &b"GET / HTTP/1.1\r\n"[..]).await.is_err());
+  }
+}
@@ -0,0 +1,307 @@
+use impulse_server_kit::prelude::*;
+use impulse_server_kit::salvo::Writer;
+use impulse_server_kit::salvo::hyper;
+use impulse_server_kit::salvo::hyper::http::uri::{PathAndQuery, Uri};
+use regex::Regex;
+
+use crate::config::{Rule, RuleAction};
+
+struct CompiledRule {
+  path: Option<Regex>,
+  methods: Vec<String>,
+  headers: Vec<(String, Regex)>,
+  query: Vec<(String, Regex)>,
+  action: RuleAction,
+}
+
+impl CompiledRule {
+  /// Regexes are checked on config load.
+  fn new(rule: &Rule) -> Option<Self> {
+    let compile = |map: &Option<std::collections::BTreeMap<String, String>>| {
+      map
+        .iter()
+        .flatten()
+        .map(|(name, regex)| Regex::new(regex).ok().map(|regex| (name.clone(), regex)))
+        .collect::<Option<Vec<_>>>()
+    };
+    Some(Self {
+      path: match &rule.r#match.path {
+        Some(path) => Some(Regex::new(path).ok()?),
+        None => None,
+      },
+      methods: rule.r#match.methods.clone().unwrap_or_default(),
+      headers: compile(&rule.r#match.headers)?,
+      query: compile(&rule.r#match.query)?,
+      action: rule.action.clone(),
+    })
+  }
+
+  fn matches(&self, req: &Request) -> bool {
+    self.path.as_ref().is_none_or(|regex| regex.is_match(req.uri().path()))
+      && (self.methods.is_empty()
+        || self
+          .methods
+          .iter()
+          .any(|m| m.eq_ignore_ascii_case(req.method().as_str())))
+      && self.headers.iter().all(|(name, regex)| {
+        req
+          .headers()
+          .get_all(name.as_str())
+          .iter()
+          .any(|v| v.to_str().is_ok_and(|v| regex.is_match(v)))
+      })
+      && self
+        .query
+        .iter()
+        .all(|(name, regex)| req.query::<String>(name).is_some_and(|v| regex.is_match(&v)))
+  }
+
+  /// Whether the rule depends on more than the path.
+  fn has_conditions(&self) -> bool {
+    !self.methods.is_empty() || !self.headers.is_empty() || !self.query.is_empty()
+  }
+
+  /// Path after the path changing action; `None` for actions finishing the request.
+  fn changed_path(&self, path: &str) -> Option<String> {
+    match &self.action {
+      RuleAction::Rewrite { path: template } => Some(match &self.path {
+        Some(regex) => regex.replace(path, template.as_str()).into_owned(),
+        None => template.clone(),
+      }),
+      RuleAction::AddPrefix { prefix } => Some(format!("{}{path}", prefix.trim_end_matches('/'))),
+      RuleAction::StripPrefix { prefix } => match path.strip_prefix(prefix.trim_end_matches('/')) {
+        Some("") => Some("/".to_string()),
+        Some(rest) if rest.starts_with('/') => Some(rest.to_string()),
+        _ => Some(path.to_string()),
+      },
+      RuleAction::Redirect { .. } | RuleAction::Respond { .. } => None,
+    }
+  }
+
+  /// Expands `$1`, `$name` in the template with groups of the path regex.
+  fn expand(&self, template: &str, path: &str) -> String {
+    match self.path.as_ref().and_then(|regex| regex.captures(path)) {
+      Some(captures) => {
+        let mut expanded = String::new();
+        captures.expand(template, &mut expanded);
+        expanded
+      }
+      None => template.to_string(),
+    }
+  }
+}
+
+/// Changes the path of the request keeping the query.
+fn set_path(req: &mut Request, path: &str) -> MResult<()> {
+  let path_and_query = match req.uri().query() {
+    Some(query) => format!("{path}?{query}"),
+    None => path.to_string(),
+  };
+  let mut parts = req.uri().clone().into_parts();
+  parts.path_and_query = Some(path_and_query.parse::<PathAndQuery>().map_err(|e| {
+    ServerError::from_private(e)
+      .with_public("Invalid rewritten path!")
+      .with_500()
+  })?);
+  *req.uri_mut() = Uri::from_parts(parts).map_err(|e| {
+    ServerError::from_private(e)
+      .with_public("Invalid rewritten URI!")
+      .with_500()
+  })?;
+  Ok(())
+}
+
+/// Applies rewrite, redirect and fixed response rules of a service in order.
+pub(crate) struct RulesHandler {
+  rules: Vec<CompiledRule>,
+}
+
+impl RulesHandler {
+  pub(crate) fn new(rules: &[Rule]) -> Self {
+    Self {
+      rules: rules.iter().filter_map(CompiledRule::new).collect(),
+    }
+  }
+
+  /// Applies the rules; returns `true` if the response is ready.
+  fn apply(&self, req: &mut Request, res: &mut Response) -> MResult<bool> {
+    for rule in &self.rules {
+      if !rule.matches(req) {
+        continue;
+      }
+      let path = req.uri().path().to_string();
+      if let Some(changed) = rule.changed_path(&path) {
+        if changed != path {
+          tracing::debug!(from = path, to = changed, "Path is rewritten");
+          set_path(req, &changed)?;
+        }
+        continue;
+      }
+      match &rule.action {
+        RuleAction::Redirect { location, status } => {
+          let status = StatusCode::from_u16(status.unwrap_or(302)).unwrap_or(StatusCode::FOUND);
+          let mut location = rule.expand(location, &path);
+          // The original query is kept unless the location sets its own.
+          if let Some(query) = req.uri().query()
+            && !location.contains('?')
+          {
+            location = format!("{location}?{query}");
+          }
+          let location = hyper::header::HeaderValue::from_str(&location).map_err(|e| {
+            ServerError::from_private(e)
+              .with_public("Invalid redirect location!")
+              .with_500()
+          })?;
+          res.status_code(status);
+          res.add_header(hyper::header::LOCATION, location, true).unwrap();
+          return Ok(true);
+        }
+        RuleAction::Respond {
+          status,
+          body,
+          content_type,
+        } => {
+          res.status_code(StatusCode::from_u16(*status).unwrap_or(StatusCode::OK));
+          res
+            .add_header(
+              hyper::header::CONTENT_TYPE,
+              content_type.as_deref().unwrap_or("text/plain; charset=utf-8"),
+              true,
+            )
+            .map_err(|e| {
+              ServerError::from_private(e)
+                .with_public("Invalid content type!")
+                .with_500()
+            })?;
+          if let Some(body) = body {
+            res.write_body(rule.expand(body, &path)).map_err(|e| {
+              ServerError::from_private(e)
+                .with_public("Can't set document body!")
+                .with_500()
+            })?;
+          }
+          return Ok(true);
+        }
+        RuleAction::Rewrite { .. } | RuleAction::AddPrefix { .. } | RuleAction::StripPrefix { .. } => {}
+      }
+    }
+    Ok(false)
+  }
+
+  /// Describes the rules applied to the path; returns the final path, or `None` if a rule finishes the request.
+  ///
+  /// Rules matching methods, headers or query are considered not applied.
+  pub(crate) fn explain(&self, path: &str) -> (Vec<String>, Option<String>) {
+    let mut described = vec![];
+    let mut path = path.to_string();
+    for (idx, rule) in self.rules.iter().enumerate() {
+      let number = idx + 1;
+      if rule.path.as_ref().is_some_and(|regex| !regex.is_match(&path)) {
+        continue;
+      }
+      if rule.has_conditions() {
+        described.push(format!(
+          "rule #{number} depends on the method, headers or query and is not applied"
+        ));
+        continue;
+      }
+      if let Some(changed) = rule.changed_path(&path) {
+        described.push(format!("rule #{number} changes the path to `{changed}`"));
+        path = changed;
+        continue;
+      }
+      match &rule.action {
+        RuleAction::Redirect { location, status } => described.push(format!(
+          "rule #{number} redirects to `{}` with `{}`",
+          rule.expand(location, &path),
+          status.unwrap_or(302)
+        )),
+        RuleAction::Respond { status, .. } => described.push(format!("rule #{number} responds with `{status}`")),
+        RuleAction::Rewrite { .. } | RuleAction::AddPrefix { .. } | RuleAction::StripPrefix { .. } => {}
+      }
+      return (described, None);
+    }
+    (described, Some(path))
+  }
+}
+
+#[impulse_server_kit::salvo::async_trait]
+impl impulse_server_kit::salvo::Handler for RulesHandler {
+  #[tracing::instrument(
+    skip_all,
+    name = "request-rules",
+    level = "debug",
+    fields(
+      http.uri = req.uri().path(),
+      http.method = req.method().as_str()
+    )
+  )]
+  async fn handle(&self, req: &mut Request, depot: &mut Depot, res: &mut Response, ctrl: &mut salvo::FlowCtrl) {
+    match self.apply(req, res) {
+      Ok(false) => ctrl.call_next(req, depot, res).await,
+      Ok(true) => ctrl.skip_rest(),
+      Err(e) => {
+        e.write(req, depot, res).await;
+        ctrl.skip_rest();
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+
+  fn handler(rules: serde_json::Value) -> RulesHandler {
+    RulesHandler::new(&serde_json::from_value::<Vec<Rule>>(rules).unwrap())
+  }
+
+  fn request(uri: &str) -> Request {
+    let mut req = Request::new();
+    *req.uri_mut() = uri.parse().unwrap();
+    req
+  }
+
+  #[test]
+  fn redirect_keeps_query() {
+    let rules = handler(json!([
+      { "match": { "path": "^/old/(.*)$" }, "action": { "type": "redirect", "location": "/new/$1", "status": 301 } },
+      { "match": { "path": "^/search$" }, "action": { "type": "redirect", "location": "/find?source=old" } },
+    ]));
+
+    let mut res = Response::new();
+    assert!(
+      rules
+        .apply(&mut request("http://example.com/old/page?id=7&sort=asc"), &mut res)
+        .unwrap()
+    );
+    assert_eq!(res.status_code, Some(StatusCode::MOVED_PERMANENTLY));
+    assert_eq!(
+      res.headers().get(hyper::header::LOCATION).unwrap(),
+      "/new/page?id=7&sort=asc"
+    );
+
+    let mut res = Response::new();
+    assert!(
+      rules
+        .apply(&mut request("http://example.com/search?q=lbrp"), &mut res)
+        .unwrap()
+    );
+    assert_eq!(res.status_code, Some(StatusCode::FOUND));
+    assert_eq!(res.headers().get(hyper::header::LOCATION).unwrap(), "/find?source=old");
+  }
+
+  #[test]
+  fn rewrite_keeps_query() {
+    let rules = handler(json!([
+      { "match": { "path": "^/v1/(.*)$" }, "action": { "type": "rewrite", "path": "/api/$1" } },
+      { "action": { "type": "strip_prefix", "prefix": "/api" } },
+    ]));
+
+    let mut req = request("http://example.com/v1/users?page=2");
+    assert!(!rules.apply(&mut req, &mut Response::new()).unwrap());
+    assert_eq!(req.uri().path(), "/users");
+    assert_eq!(req.uri().query(), Some("page=2"));
+  }
+}
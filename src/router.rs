@@ -1,10 +1,20 @@
 use impulse_server_kit::prelude::*;
 use impulse_server_kit::salvo::prelude::{Compression, CompressionLevel};
+use regex::Regex;
 
-use crate::config::{LbrpConfig, Service};
+use crate::cluster::registry::NodeRegistry;
+use crate::cluster::{ClusterRuntime, request_host, strip_port};
+use crate::config::{CommonService, LbrpConfig, LbrpMode, LbrpPCMode, LbrpYBOBMode, Service};
 use crate::cors_handling::CorsHandler;
-use crate::error_handling::{ERR_HANDLER, error_files_handler, error_index_handler, proxied_error_handler};
-use crate::proxy_client::{ModifiedReqwestClient, ProxyProvider};
+use crate::error_handling::{
+  ERR_HANDLER, GatewayErrors, error_files_handler, error_index_handler, proxied_error_handler,
+};
+use crate::header_handling::{ForwardedHeaders, HeadersHandler, TrustedProxies};
+use crate::health_checking::spawn_health_checker;
+use crate::proxy_client::{
+  ModifiedReqwestClient, ProxyProvider, RequestPolicy, ServiceProxy, upstream_client, upstream_pool, upstream_sockets,
+};
+use crate::request_rules::RulesHandler;
 
 pub fn excluded_from_err_handling(services: &[Service]) -> Vec<String> {
   services
@@ -26,12 +36,40 @@ pub fn excluded_from_err_handling(services: &[Service]) -> Vec<String> {
     .collect::<Vec<_>>()
 }
 
-pub async fn get_router_from_config(config: &LbrpConfig, children: &mut Vec<std::process::Child>) -> Router {
-  for child in children.iter_mut() {
-    child.kill().unwrap();
+/// Whether building a router may start health checkers and install the error handler.
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum BuildMode {
+  Serve,
+  /// Only describes the router, e.g. for `dump-router`.
+  Describe,
+}
+
+/// Builds the router for the node's mode; cluster modes start their tasks in `runtime`.
+pub async fn mode_router(config: &LbrpConfig, runtime: &mut ClusterRuntime) -> Router {
+  match &config.lbrp_mode {
+    LbrpMode::PC(LbrpPCMode::Parent) => crate::cluster::pc::start_parent(config, runtime),
+    LbrpMode::Ybob(LbrpYBOBMode::OlderBrother) => crate::cluster::ybob::start_older_brother(config, runtime).await,
+    _ => build_router(config, BuildMode::Serve).await,
+  }
+}
+
+/// Builds the same router as [`mode_router`] without starting anything or touching the global state.
+pub(crate) async fn describe_router(config: &LbrpConfig) -> Router {
+  let registry = || {
+    let cluster = config.cluster.clone().unwrap_or_default();
+    std::sync::Arc::new(NodeRegistry::new(cluster.heartbeat_timeout()))
+  };
+  match &config.lbrp_mode {
+    LbrpMode::PC(LbrpPCMode::Parent) => crate::cluster::pc::parent_router(registry()),
+    LbrpMode::Ybob(LbrpYBOBMode::OlderBrother) => {
+      crate::cluster::ybob::older_brother_router(config, registry(), BuildMode::Describe).await
+    }
+    _ => build_router(config, BuildMode::Describe).await,
   }
-  children.clear();
+}
 
+/// Builds the router for services of the config without starting them.
+pub async fn build_router(config: &LbrpConfig, mode: BuildMode) -> Router {
   let mut router = Router::with_hoop(Compression::new().disable_all().enable_zstd(CompressionLevel::Fastest));
 
   if let Some(Service::ErrorHandler(err_handler)) =
@@ -43,74 +81,401 @@ pub async fn get_router_from_config(config: &LbrpConfig, children: &mut Vec<std:
       .push(Router::new().path("/403").get(error_index_handler))
       .push(Router::new().path("/404").get(error_index_handler))
       .push(Router::new().path("/405").get(error_index_handler))
+      .push(Router::new().path("/413").get(error_index_handler))
       .push(Router::new().path("/423").get(error_index_handler))
       .push(Router::new().path("/500").get(error_index_handler))
+      .push(Router::new().path("/502").get(error_index_handler))
+      .push(Router::new().path("/503").get(error_index_handler))
+      .push(Router::new().path("/504").get(error_index_handler))
       .push(Router::new().path("/oops").get(error_index_handler));
 
     for file in &err_handler.static_files {
       router = router.push(Router::new().path(format!("/{file}")).get(error_files_handler));
     }
 
-    let mut err_handler = err_handler.clone();
-    err_handler.static_files = err_handler
-      .static_files
-      .into_iter()
-      .map(|fp| format!("/{fp}"))
-      .collect::<_>();
+    if mode == BuildMode::Serve {
+      let mut err_handler = err_handler.clone();
+      err_handler.static_files = err_handler
+        .static_files
+        .into_iter()
+        .map(|fp| format!("/{fp}"))
+        .collect::<_>();
 
-    let mut guard = ERR_HANDLER.as_ref().lock().await;
-    *guard = Some(err_handler);
+      let mut guard = ERR_HANDLER.as_ref().lock().await;
+      *guard = Some(err_handler);
+    }
   }
 
-  for service in &config.services {
-    if let Service::CommonService(service) = service {
-      if service.should_startup() {
-        children.push(service.startup().unwrap());
-      }
+  let mut hosts = Vec::<(&str, Vec<(PathMatcher, &CommonService)>)>::new();
+  for service in config.common_services() {
+    let matcher = PathMatcher::new(service);
+    match hosts.iter_mut().find(|(host, _)| *host == service.from) {
+      Some((_, services)) => services.push((matcher, service)),
+      None => hosts.push((service.from.as_str(), vec![(matcher, service)])),
+    }
+  }
+  let mut hosts = hosts
+    .into_iter()
+    .map(|(host, services)| {
+      let pattern =
+        HostPattern::parse(host).expect("host patterns are checked by `LbrpConfig::validate` on config load");
+      (pattern, host, services)
+    })
+    .collect::<Vec<_>>();
+  hosts.sort_by_key(|(pattern, _, _)| pattern.precedence());
 
-      let mut service_router = Router::new().host(service.from.clone());
+  let trusted_proxies = std::sync::Arc::new(TrustedProxies::new(
+    config.trusted_proxies.as_deref().unwrap_or_default(),
+  ));
+  for (pattern, host, mut services) in hosts {
+    services.sort_by_key(|(matcher, _)| matcher.precedence());
+    let pattern = std::sync::Arc::new(pattern);
+    let mut host_router = match pattern.as_ref() {
+      HostPattern::Exact(_) => Router::new().host(host.to_string()),
+      _ => Router::new().filter_fn({
+        let pattern = pattern.clone();
+        move |req, _| pattern.matches(&request_host(req))
+      }),
+    };
+    for (matcher, service) in services {
+      host_router = host_router.push(service_router(config, service, &pattern, matcher, &trusted_proxies, mode));
+    }
+    router = router.push(host_router);
+  }
 
-      if let Some(header_name) = service.provide_ip_as_header.as_deref() {
-        service_router = service_router.hoop(ProxyProvider {
-          header_name: header_name.to_string(),
-        });
-      }
+  router
+}
 
-      #[cfg(feature = "authnz")]
-      if let Some(tags) = &service.require_subdomain_auth {
-        service_router = service_router
-          .hoop(crate::authnz::MaybeC3ARedirect::new(tags.clone()))
-          .push(crate::authnz::auth_router());
-      }
+/// Hosts served by a service, parsed from its `from`.
+pub(crate) enum HostPattern {
+  Exact(String),
+  /// `*.example.com`; every `*` matches a single label, available as `{1}`, `{2}`, ... in upstream URLs.
+  Wildcard(Regex),
+  /// `~<regex>`; numbered and named groups are available in upstream URLs.
+  Regex(Regex),
+  /// `*`: any host which isn't served by other services.
+  Any,
+}
 
-      let mut rest_router = if let Some(Service::CommonStatic(r#static)) =
-        &config.services.iter().find(|v| matches!(v, Service::CommonStatic(_)))
-      {
-        Router::with_path("{**rest_path}")
-          .hoop(
-            impulse_static_server::StaticRouter::new(&r#static.path)
-              .unwrap()
-              .with_routes_list(r#static.static_routes.clone()),
-          )
-          .goal(ModifiedReqwestClient::new_client(service.to.clone(), &service.from))
-      } else {
-        Router::with_path("{**rest_path}").goal(ModifiedReqwestClient::new_client(service.to.clone(), &service.from))
-      };
+impl HostPattern {
+  pub(crate) fn parse(from: &str) -> Result<Self, regex::Error> {
+    Ok(if from == "*" {
+      Self::Any
+    } else if let Some(regex) = from.strip_prefix('~') {
+      Self::Regex(Regex::new(regex)?)
+    } else if from.contains('*') {
+      let labels = strip_port(from).split('*').map(regex::escape).collect::<Vec<_>>();
+      Self::Wildcard(Regex::new(&format!("(?i)^{}$", labels.join("([^.]+)")))?)
+    } else {
+      Self::Exact(strip_port(from).to_string())
+    })
+  }
 
-      if config.services.iter().any(|s| matches!(s, Service::ErrorHandler(_)))
-        && !service.skip_err_handling.is_some_and(|v| v)
-      {
-        rest_router = rest_router.hoop(proxied_error_handler);
+  /// Captures of the pattern in the host without port; `None` if the host doesn't match.
+  pub(crate) fn captures(&self, host: &str) -> Option<Vec<(String, String)>> {
+    match self {
+      Self::Exact(exact) => exact.eq_ignore_ascii_case(host).then(Vec::new),
+      Self::Any => Some(vec![]),
+      Self::Wildcard(regex) | Self::Regex(regex) => {
+        let found = regex.captures(host)?;
+        let mut captures = vec![];
+        for (idx, name) in regex.capture_names().enumerate().skip(1) {
+          if let Some(value) = found.get(idx) {
+            captures.push((idx.to_string(), value.as_str().to_string()));
+            if let Some(name) = name {
+              captures.push((name.to_string(), value.as_str().to_string()));
+            }
+          }
+        }
+        Some(captures)
       }
+    }
+  }
 
-      if let Some(origins) = service.cors_domains.as_ref().cloned() {
-        rest_router = rest_router.hoop(CorsHandler::new(origins, config.cors_opts.clone()));
-      }
+  pub(crate) fn matches(&self, host: &str) -> bool {
+    match self {
+      Self::Exact(exact) => exact.eq_ignore_ascii_case(host),
+      Self::Any => true,
+      Self::Wildcard(regex) | Self::Regex(regex) => regex.is_match(host),
+    }
+  }
 
-      service_router = service_router.push(rest_router);
-      router = router.push(service_router);
+  /// Names available as placeholders in upstream URLs.
+  pub(crate) fn capture_names(&self) -> Vec<String> {
+    match self {
+      Self::Exact(_) | Self::Any => vec![],
+      Self::Wildcard(regex) | Self::Regex(regex) => regex
+        .capture_names()
+        .enumerate()
+        .skip(1)
+        .flat_map(|(idx, name)| std::iter::once(idx.to_string()).chain(name.map(|n| n.to_string())))
+        .collect(),
     }
   }
 
-  router
+  /// Sort key: exact hosts first, then wildcards, regexes and finally the fallback.
+  pub(crate) fn precedence(&self) -> u8 {
+    match self {
+      Self::Exact(_) => 0,
+      Self::Wildcard(_) => 1,
+      Self::Regex(_) => 2,
+      Self::Any => 3,
+    }
+  }
+}
+
+/// Names of `{name}` placeholders in the upstream URL.
+pub(crate) fn placeholders(template: &str) -> Vec<&str> {
+  template
+    .split('{')
+    .skip(1)
+    .filter_map(|part| part.split_once('}').map(|(name, _)| name))
+    .collect()
+}
+
+/// Substitutes captures of the host pattern into `{name}` placeholders of the upstream URL.
+pub(crate) fn fill_placeholders(template: &str, captures: &[(String, String)]) -> String {
+  captures.iter().fold(template.to_string(), |url, (name, value)| {
+    url.replace(&format!("{{{name}}}"), value)
+  })
+}
+
+/// Paths of a host served by a service.
+pub(crate) struct PathMatcher {
+  prefix: String,
+  regex: Option<Regex>,
+  strip: bool,
+}
+
+impl PathMatcher {
+  pub(crate) fn new(service: &CommonService) -> Self {
+    Self {
+      prefix: service.path_prefix().to_string(),
+      // The regex is checked on config load.
+      regex: service.path_regex.as_deref().and_then(|r| Regex::new(r).ok()),
+      strip: service.strip_path_prefix.is_some_and(|v| v),
+    }
+  }
+
+  pub(crate) fn matches(&self, path: &str) -> bool {
+    path
+      .strip_prefix(self.prefix.as_str())
+      .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+      && self.regex.as_ref().is_none_or(|regex| regex.is_match(path))
+  }
+
+  /// Path forwarded to upstreams.
+  pub(crate) fn upstream_path<'a>(&self, path: &'a str) -> &'a str {
+    match path.strip_prefix(self.prefix.as_str()) {
+      Some(rest) if self.strip => rest,
+      _ => path,
+    }
+  }
+
+  /// Path forwarded to upstreams, without the leading slash.
+  pub(crate) fn forwarded_path(&self, req: &Request) -> Option<String> {
+    Some(self.upstream_path(req.uri().path()).trim_start_matches('/').to_owned())
+  }
+
+  /// Sort key: longer prefixes first, then services with a regex.
+  pub(crate) fn precedence(&self) -> (std::cmp::Reverse<usize>, bool) {
+    (std::cmp::Reverse(self.prefix.len()), self.regex.is_none())
+  }
+}
+
+fn service_router(
+  config: &LbrpConfig,
+  service: &CommonService,
+  pattern: &std::sync::Arc<HostPattern>,
+  matcher: PathMatcher,
+  trusted_proxies: &std::sync::Arc<TrustedProxies>,
+  mode: BuildMode,
+) -> Router {
+  let mut service_router = Router::new();
+
+  if service.forwarded_headers.is_some_and(|v| v) {
+    service_router = service_router.hoop(ForwardedHeaders::new(trusted_proxies.clone()));
+  }
+
+  if let Some(provider) = service.provide_ip_as_header.as_deref().and_then(ProxyProvider::new) {
+    service_router = service_router.hoop(provider);
+  }
+
+  // User header operations go last, so they can override the forwarded headers.
+  if service.request_headers.is_some() || service.response_headers.is_some() {
+    service_router = service_router.hoop(HeadersHandler::new(
+      service.request_headers.as_ref(),
+      service.response_headers.as_ref(),
+    ));
+  }
+
+  #[cfg(feature = "authnz")]
+  if let Some(tags) = &service.require_subdomain_auth {
+    service_router = service_router
+      .hoop(crate::authnz::MaybeC3ARedirect::new(tags.clone()))
+      .push(crate::authnz::auth_router());
+  }
+
+  // The pool is shared with the previous config, which has already started its health checks then.
+  let pool = upstream_pool(service);
+  if let Some(health_check) = &service.health_check
+    && mode == BuildMode::Serve
+    && pool.start_health_checks()
+  {
+    spawn_health_checker(service.service_name.clone(), &pool, health_check.clone());
+  }
+
+  let mut rest_router = Router::with_path("{**rest_path}");
+  if let Some(rules) = &service.rules {
+    rest_router = rest_router.hoop(RulesHandler::new(rules));
+  }
+  if let Some(Service::CommonStatic(r#static)) = &config.services.iter().find(|v| matches!(v, Service::CommonStatic(_)))
+  {
+    rest_router = rest_router.hoop(
+      impulse_static_server::StaticRouter::new(&r#static.path)
+        .unwrap()
+        .with_routes_list(r#static.static_routes.clone()),
+    );
+  }
+
+  let matcher = std::sync::Arc::new(matcher);
+  rest_router = match pattern.as_ref() {
+    HostPattern::Exact(_) if service.send_proxy_protocol.is_none() => rest_router.goal(
+      ModifiedReqwestClient::new_client(
+        upstream_client(service),
+        upstream_sockets(service),
+        pool,
+        std::sync::Arc::new(RequestPolicy::new(service)),
+        &service.from,
+      )
+      .with_url_path_getter({
+        let matcher = matcher.clone();
+        move |req: &Request, _: &Depot| matcher.forwarded_path(req)
+      }),
+    ),
+    _ => rest_router.goal(ServiceProxy::new(service, pattern.clone(), pool, matcher.clone())),
+  };
+  rest_router = rest_router.filter_fn(move |req, _| matcher.matches(req.uri().path()));
+
+  if config.services.iter().any(|s| matches!(s, Service::ErrorHandler(_)))
+    && !service.skip_err_handling.is_some_and(|v| v)
+  {
+    rest_router = rest_router.hoop(proxied_error_handler);
+  }
+
+  if let Some(origins) = service.cors_domains.as_ref().cloned() {
+    rest_router = rest_router.hoop(CorsHandler::new(origins, config.cors_opts.clone()));
+  }
+
+  service_router.push(rest_router.hoop(GatewayErrors))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn matcher(prefix: &str, regex: Option<&str>, strip: bool) -> PathMatcher {
+    PathMatcher {
+      prefix: prefix.to_string(),
+      regex: regex.map(|r| Regex::new(r).unwrap()),
+      strip,
+    }
+  }
+
+  #[test]
+  fn prefix_matches_whole_segments() {
+    let matcher = matcher("/v1", None, false);
+    assert!(matcher.matches("/v1"));
+    assert!(matcher.matches("/v1/users"));
+    assert!(!matcher.matches("/v10/users"));
+    assert!(!matcher.matches("/v2"));
+    assert!(self::matcher("", None, false).matches("/anything"));
+  }
+
+  #[test]
+  fn regex_matches_whole_path() {
+    let matcher = matcher("/api", Some(r"^/api/\d+$"), false);
+    assert!(matcher.matches("/api/42"));
+    assert!(!matcher.matches("/api/users"));
+    assert!(!matcher.matches("/other/42"));
+  }
+
+  #[test]
+  fn prefix_is_stripped_only_if_asked() {
+    assert_eq!(matcher("/v1", None, true).upstream_path("/v1/users"), "/users");
+    assert_eq!(matcher("/v1", None, true).upstream_path("/v1"), "");
+    assert_eq!(matcher("/v1", None, false).upstream_path("/v1/users"), "/v1/users");
+  }
+
+  #[test]
+  fn longer_prefixes_and_regexes_go_first() {
+    let mut matchers = [
+      matcher("", None, false),
+      matcher("/v1", None, false),
+      matcher("/v1", Some("^/v1/a"), false),
+      matcher("/v1/admin", None, false),
+    ];
+    matchers.sort_by_key(PathMatcher::precedence);
+    let order = matchers
+      .iter()
+      .map(|m| (m.prefix.as_str(), m.regex.is_some()))
+      .collect::<Vec<_>>();
+    assert_eq!(order, [("/v1/admin", false), ("/v1", true), ("/v1", false), ("", false)]);
+  }
+
+  #[test]
+  fn host_patterns_are_ordered_by_precedence() {
+    let mut patterns = ["*", "~^.+\\.example\\.com$", "*.example.com", "example.com"]
+      .map(|from| HostPattern::parse(from).unwrap());
+    patterns.sort_by_key(HostPattern::precedence);
+    assert!(matches!(
+      patterns,
+      [
+        HostPattern::Exact(_),
+        HostPattern::Wildcard(_),
+        HostPattern::Regex(_),
+        HostPattern::Any
+      ]
+    ));
+  }
+
+  #[test]
+  fn exact_host_ignores_case_and_port() {
+    let pattern = HostPattern::parse("Example.com:443").unwrap();
+    assert!(pattern.matches("example.COM"));
+    assert_eq!(pattern.captures("example.com"), Some(vec![]));
+    assert_eq!(pattern.captures("api.example.com"), None);
+  }
+
+  #[test]
+  fn wildcard_captures_single_labels() {
+    let pattern = HostPattern::parse("*.*.example.com").unwrap();
+    assert_eq!(
+      pattern.captures("api.eu.example.com"),
+      Some(vec![("1".to_string(), "api".to_string()), ("2".to_string(), "eu".to_string())])
+    );
+    assert!(!pattern.matches("eu.example.com"));
+    assert!(!pattern.matches("api.eu.example.org"));
+    assert_eq!(pattern.capture_names(), ["1", "2"]);
+  }
+
+  #[test]
+  fn regex_captures_numbered_and_named_groups() {
+    let pattern = HostPattern::parse(r"~^(?P<app>[a-z]+)\.apps\.example\.com$").unwrap();
+    let captures = pattern.captures("shop.apps.example.com").unwrap();
+    assert_eq!(
+      captures,
+      [("1".to_string(), "shop".to_string()), ("app".to_string(), "shop".to_string())]
+    );
+    assert_eq!(
+      fill_placeholders("http://{app}.internal:{1}", &captures),
+      "http://shop.internal:shop"
+    );
+    assert_eq!(placeholders("http://{app}.internal/{1}"), ["app", "1"]);
+  }
+
+  #[test]
+  fn invalid_regex_host_is_rejected() {
+    assert!(HostPattern::parse("~(unclosed").is_err());
+  }
 }
@@ -0,0 +1,226 @@
+use impulse_server_kit::prelude::*;
+use impulse_server_kit::salvo::affix_state;
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWriteExt, BufReader};
+use tokio::process::Child;
+use tokio::sync::broadcast;
+
+use crate::cluster::ClusterSecret;
+use crate::config::ServiceLogs;
+
+/// Path of admin routes served by every node.
+pub(crate) const ADMIN_API: &str = "/--lbrp-admin";
+
+/// Log file which is rotated to `<name>.log.1`, `<name>.log.2`, ... after reaching `max_size`.
+struct RotatingFile {
+  path: PathBuf,
+  max_size: u64,
+  max_files: u32,
+  file: Option<tokio::fs::File>,
+  size: u64,
+}
+
+impl RotatingFile {
+  fn rotated(&self, idx: u32) -> PathBuf {
+    let mut path = self.path.as_os_str().to_owned();
+    path.push(format!(".{idx}"));
+    PathBuf::from(path)
+  }
+
+  async fn rotate(&mut self) -> std::io::Result<()> {
+    if let Some(mut file) = self.file.take() {
+      file.flush().await?;
+    }
+    for idx in (1..self.max_files).rev() {
+      let from = self.rotated(idx);
+      if tokio::fs::try_exists(&from).await? {
+        tokio::fs::rename(from, self.rotated(idx + 1)).await?;
+      }
+    }
+    if self.max_files > 0 {
+      tokio::fs::rename(&self.path, self.rotated(1)).await
+    } else {
+      tokio::fs::remove_file(&self.path).await
+    }
+  }
+
+  async fn write_line(&mut self, line: &str) -> std::io::Result<()> {
+    let len = line.len() as u64 + 1;
+    if self.file.is_some() && self.size + len > self.max_size {
+      self.rotate().await?;
+    }
+    let file = match self.file.take() {
+      Some(file) => file,
+      None => {
+        let file = tokio::fs::OpenOptions::new()
+          .create(true)
+          .append(true)
+          .open(&self.path)
+          .await?;
+        self.size = file.metadata().await?.len();
+        file
+      }
+    };
+    let file = self.file.insert(file);
+    file.write_all(line.as_bytes()).await?;
+    file.write_all(b"\n").await?;
+    file.flush().await?;
+    self.size += len;
+    Ok(())
+  }
+}
+
+/// Captured output of a service process, kept across its restarts.
+pub(crate) struct ServiceOutput {
+  service_name: String,
+  tail: Mutex<VecDeque<String>>,
+  tail_lines: usize,
+  file: Option<tokio::sync::Mutex<RotatingFile>>,
+  lines: broadcast::Sender<String>,
+}
+
+impl ServiceOutput {
+  pub(crate) fn new(service_name: &str, settings: &ServiceLogs) -> Self {
+    Self {
+      service_name: service_name.to_owned(),
+      tail: Mutex::new(VecDeque::new()),
+      tail_lines: settings.tail_lines(),
+      file: settings.dir.as_ref().map(|dir| {
+        tokio::sync::Mutex::new(RotatingFile {
+          path: dir.join(format!("{service_name}.log")),
+          max_size: settings.max_size(),
+          max_files: settings.max_files(),
+          file: None,
+          size: 0,
+        })
+      }),
+      lines: broadcast::channel(64).0,
+    }
+  }
+
+  /// Subscribes to output lines, e.g. to wait for a readiness message.
+  pub(crate) fn subscribe(&self) -> broadcast::Receiver<String> {
+    self.lines.subscribe()
+  }
+
+  /// Spawns tasks draining stdout and stderr of the process until they are closed.
+  pub(crate) fn capture(self: &Arc<Self>, child: &mut Child) {
+    if let Some(stdout) = child.stdout.take() {
+      tokio::spawn(self.clone().drain(stdout, "stdout"));
+    }
+    if let Some(stderr) = child.stderr.take() {
+      tokio::spawn(self.clone().drain(stderr, "stderr"));
+    }
+  }
+
+  async fn drain(self: Arc<Self>, stream: impl AsyncRead + Unpin, stream_name: &'static str) {
+    let mut reader = BufReader::new(stream);
+    let mut buf = vec![];
+    loop {
+      buf.clear();
+      match reader.read_until(b'\n', &mut buf).await {
+        Ok(0) => break,
+        Ok(_) => {
+          let line = String::from_utf8_lossy(&buf);
+          self.record(stream_name, line.trim_end_matches(['\r', '\n'])).await;
+        }
+        Err(e) => {
+          tracing::warn!(
+            service = self.service_name,
+            stream = stream_name,
+            error = ?e,
+            "Can't read service output"
+          );
+          break;
+        }
+      }
+    }
+  }
+
+  async fn record(&self, stream_name: &'static str, line: &str) {
+    tracing::info!(service = self.service_name, stream = stream_name, "{}", line);
+    if self.lines.receiver_count() > 0 {
+      let _ = self.lines.send(line.to_owned());
+    }
+
+    if self.tail_lines > 0 {
+      let mut tail = self.tail.lock().unwrap();
+      if tail.len() >= self.tail_lines {
+        tail.pop_front();
+      }
+      tail.push_back(line.to_owned());
+    }
+
+    if let Some(file) = &self.file
+      && let Err(e) = file.lock().await.write_line(line).await
+    {
+      tracing::warn!(service = self.service_name, error = ?e, "Can't write service log file");
+    }
+  }
+
+  pub(crate) fn tail(&self, lines: usize) -> Vec<String> {
+    let tail = self.tail.lock().unwrap();
+    tail.iter().skip(tail.len().saturating_sub(lines)).cloned().collect()
+  }
+}
+
+/// Captured outputs of managed services, keyed by service name.
+#[derive(Clone, Default)]
+pub(crate) struct ServiceOutputs(Arc<RwLock<HashMap<String, Arc<ServiceOutput>>>>);
+
+impl ServiceOutputs {
+  pub(crate) fn insert(&self, service_name: String, output: Arc<ServiceOutput>) {
+    self.0.write().unwrap().insert(service_name, output);
+  }
+
+  pub(crate) fn remove(&self, service_name: &str) {
+    self.0.write().unwrap().remove(service_name);
+  }
+
+  fn get(&self, service_name: &str) -> Option<Arc<ServiceOutput>> {
+    self.0.read().unwrap().get(service_name).cloned()
+  }
+}
+
+#[derive(Serialize)]
+struct ServiceLogsTail {
+  service_name: String,
+  lines: Vec<String>,
+}
+
+fn extract_service_outputs(depot: &Depot) -> MResult<&ServiceOutputs> {
+  depot
+    .obtain::<ServiceOutputs>()
+    .map_err(|_| ServerError::from_private_str("Can't get service outputs from depot!").with_500())
+}
+
+#[handler]
+#[tracing::instrument(skip_all, name = "service-logs")]
+async fn service_logs(depot: &mut Depot, req: &mut Request) -> MResult<Json<ServiceLogsTail>> {
+  let service_name = req
+    .param::<String>("service_name")
+    .ok_or(ServerError::from_public("There is no service name!").with_400())?;
+  let lines = req.query::<usize>("lines").unwrap_or(100);
+  let output = extract_service_outputs(depot)?
+    .get(&service_name)
+    .ok_or(ServerError::from_public("There is no such managed service!").with_404())?;
+
+  json!(ServiceLogsTail {
+    lines: output.tail(lines),
+    service_name,
+  })
+}
+
+/// Admin routes guarded by the `secret`.
+///
+/// Last lines of a managed service output: `GET logs/{service_name}?lines=N`.
+pub(crate) fn admin_router(secret: String, outputs: ServiceOutputs) -> Router {
+  Router::with_path(ADMIN_API).hoop(ClusterSecret::new(Some(secret))).push(
+    Router::with_path("logs/{service_name}")
+      .hoop(affix_state::inject(outputs))
+      .get(service_logs),
+  )
+}
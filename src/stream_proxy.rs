@@ -0,0 +1,485 @@
+use rustls::ServerConfig;
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::Acceptor;
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf, copy_bidirectional};
+use tokio::net::{TcpListener, TcpStream, UdpSocket, UnixStream};
+use tokio::task::JoinSet;
+use tokio_rustls::LazyConfigAcceptor;
+
+use crate::config::{StreamService, StreamTls, TlsCertificate};
+use crate::proxy_client::{ConnectionGuard, UpstreamPool};
+use crate::router::HostPattern;
+
+/// Time without datagrams after which a UDP session is closed if `idle_timeout` is not specified.
+const DEFAULT_UDP_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+/// UDP sessions served at once if `max_sessions` is not specified.
+const DEFAULT_UDP_MAX_SESSIONS: usize = 1024;
+/// Largest UDP payload.
+const MAX_DATAGRAM_SIZE: usize = 65_535;
+/// Time given to a client to complete the TLS handshake.
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+trait StreamIo: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> StreamIo for T {}
+
+/// Certificates and upstreams chosen by the server name of TLS clients.
+struct SniRouting {
+  /// Sorted by pattern precedence.
+  certificates: Vec<(HostPattern, Arc<ServerConfig>)>,
+  /// The first certificate of the config, for clients without a matching server name.
+  default_certificate: Arc<ServerConfig>,
+  /// Sorted by pattern precedence.
+  routes: Vec<(HostPattern, Arc<UpstreamPool>)>,
+}
+
+/// Forwards connections or datagrams of a stream service to its upstreams.
+struct StreamProxy {
+  pool: Arc<UpstreamPool>,
+  tls: Option<SniRouting>,
+  connect_timeout: Option<Duration>,
+  idle_timeout: Option<Duration>,
+}
+
+fn invalid_pattern(sni: &str, e: regex::Error) -> io::Error {
+  io::Error::new(
+    io::ErrorKind::InvalidInput,
+    format!("invalid `sni` pattern `{sni}`: {e}"),
+  )
+}
+
+fn server_config(provider: &Arc<CryptoProvider>, certificate: &TlsCertificate) -> io::Result<Arc<ServerConfig>> {
+  let chain = CertificateDer::pem_file_iter(&certificate.cert)
+    .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+    .map_err(io::Error::other)?;
+  let key = PrivateKeyDer::from_pem_file(&certificate.key).map_err(io::Error::other)?;
+  let config = ServerConfig::builder_with_provider(provider.clone())
+    .with_safe_default_protocol_versions()
+    .map_err(io::Error::other)?
+    .with_no_client_auth()
+    .with_single_cert(chain, key)
+    .map_err(io::Error::other)?;
+  Ok(Arc::new(config))
+}
+
+impl SniRouting {
+  fn new(service: &StreamService, tls: &StreamTls) -> io::Result<Self> {
+    let provider = CryptoProvider::get_default()
+      .cloned()
+      .unwrap_or_else(|| Arc::new(rustls::crypto::aws_lc_rs::default_provider()));
+
+    let mut certificates = vec![];
+    for certificate in &tls.certificates {
+      let pattern = HostPattern::parse(&certificate.sni).map_err(|e| invalid_pattern(&certificate.sni, e))?;
+      let config = server_config(&provider, certificate).map_err(|e| {
+        io::Error::new(
+          e.kind(),
+          format!("can't load certificate `{}`: {e}", certificate.cert.display()),
+        )
+      })?;
+      certificates.push((pattern, config));
+    }
+    let default_certificate = certificates
+      .first()
+      .map(|(_, config)| config.clone())
+      .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "there are no certificates"))?;
+    certificates.sort_by_key(|(pattern, _)| pattern.precedence());
+
+    let mut routes = vec![];
+    for route in tls.routes.iter().flatten() {
+      let pattern = HostPattern::parse(&route.sni).map_err(|e| invalid_pattern(&route.sni, e))?;
+      let pool = UpstreamPool::new(
+        &route.to,
+        None,
+        route.balancing.clone().unwrap_or_default(),
+        service.circuit_breaker.clone(),
+      );
+      routes.push((pattern, Arc::new(pool)));
+    }
+    routes.sort_by_key(|(pattern, _)| pattern.precedence());
+
+    Ok(Self {
+      certificates,
+      default_certificate,
+      routes,
+    })
+  }
+
+  fn certificate(&self, server_name: Option<&str>) -> Arc<ServerConfig> {
+    server_name
+      .and_then(|name| self.certificates.iter().find(|(pattern, _)| pattern.matches(name)))
+      .map(|(_, config)| config.clone())
+      .unwrap_or_else(|| self.default_certificate.clone())
+  }
+
+  fn route(&self, server_name: Option<&str>) -> Option<&Arc<UpstreamPool>> {
+    let server_name = server_name?;
+    self
+      .routes
+      .iter()
+      .find(|(pattern, _)| pattern.matches(server_name))
+      .map(|(_, pool)| pool)
+  }
+}
+
+/// Stream which remembers when data were read from it last time.
+struct Watched<S> {
+  inner: S,
+  last_active: Arc<Mutex<Instant>>,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Watched<S> {
+  fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+    let filled = buf.filled().len();
+    let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+    if buf.filled().len() > filled {
+      *self.last_active.lock().unwrap() = Instant::now();
+    }
+    poll
+  }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Watched<S> {
+  fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+    Pin::new(&mut self.inner).poll_write(cx, buf)
+  }
+
+  fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+    Pin::new(&mut self.inner).poll_flush(cx)
+  }
+
+  fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+    Pin::new(&mut self.inner).poll_shutdown(cx)
+  }
+}
+
+/// Waits until nothing has happened for `idle_timeout` since `last_active`.
+async fn idle(last_active: &Mutex<Instant>, idle_timeout: Duration) {
+  loop {
+    let idle_for = last_active.lock().unwrap().elapsed();
+    if idle_for >= idle_timeout {
+      return;
+    }
+    tokio::time::sleep(idle_timeout - idle_for).await;
+  }
+}
+
+/// Copies data in both directions until both sides are closed or the connection is idle for too long.
+async fn relay(
+  mut client: Box<dyn StreamIo>,
+  mut upstream: Box<dyn StreamIo>,
+  idle_timeout: Option<Duration>,
+) -> io::Result<(u64, u64)> {
+  let Some(idle_timeout) = idle_timeout else {
+    return copy_bidirectional(&mut client, &mut upstream).await;
+  };
+  let last_active = Arc::new(Mutex::new(Instant::now()));
+  let mut client = Watched {
+    inner: client,
+    last_active: last_active.clone(),
+  };
+  let mut upstream = Watched {
+    inner: upstream,
+    last_active: last_active.clone(),
+  };
+  tokio::select! {
+    copied = copy_bidirectional(&mut client, &mut upstream) => copied,
+    () = idle(&last_active, idle_timeout) => Err(io::ErrorKind::TimedOut.into()),
+  }
+}
+
+/// Resolves addresses of UDP upstreams once, so datagrams of other clients never wait for DNS.
+async fn resolve_udp_upstreams(pool: &UpstreamPool) -> Vec<Option<SocketAddr>> {
+  let mut addrs = vec![];
+  for node in pool.nodes() {
+    let addr = match tokio::net::lookup_host(&node.url).await {
+      Ok(mut resolved) => resolved.next(),
+      Err(e) => {
+        tracing::error!(upstream = node.url, error = ?e, "Can't resolve UDP upstream");
+        None
+      }
+    };
+    addrs.push(addr);
+  }
+  addrs
+}
+
+/// Connects a new UDP socket to the upstream address.
+async fn connect_udp(addr: Option<SocketAddr>) -> io::Result<UdpSocket> {
+  let addr = addr.ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "upstream address is not resolved"))?;
+  let socket = UdpSocket::bind(if addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" }).await?;
+  socket.connect(addr).await?;
+  Ok(socket)
+}
+
+/// Datagrams of a single client, forwarded to the upstream elected for it.
+struct UdpSession {
+  upstream: UdpSocket,
+  last_active: Mutex<Instant>,
+}
+
+type UdpSessions = Arc<Mutex<HashMap<SocketAddr, Arc<UdpSession>>>>;
+
+impl StreamProxy {
+  fn new(service: &StreamService) -> io::Result<Self> {
+    let pool = UpstreamPool::new(
+      &service.to,
+      None,
+      service.balancing.clone().unwrap_or_default(),
+      service.circuit_breaker.clone(),
+    );
+    Ok(Self {
+      pool: Arc::new(pool),
+      tls: service
+        .tls
+        .as_ref()
+        .map(|tls| SniRouting::new(service, tls))
+        .transpose()?,
+      connect_timeout: service.connect_timeout.map(Duration::from_secs),
+      idle_timeout: service.idle_timeout.map(Duration::from_secs),
+    })
+  }
+
+  /// Elects an upstream of the pool for the client and connects to it.
+  async fn connect(
+    &self,
+    pool: &Arc<UpstreamPool>,
+    peer: SocketAddr,
+  ) -> io::Result<(Box<dyn StreamIo>, ConnectionGuard)> {
+    let idx = pool
+      .select_for_peer(peer.ip())
+      .ok_or_else(|| io::Error::new(io::ErrorKind::NotConnected, "every upstream is unavailable"))?;
+    let node = &pool.nodes()[idx];
+    pool.breaker_acquire(idx);
+    let guard = pool.track_connection(idx);
+
+    let connect = async {
+      Ok::<Box<dyn StreamIo>, io::Error>(match &node.socket {
+        Some(socket) => Box::new(UnixStream::connect(socket).await?),
+        None => Box::new(TcpStream::connect(&node.url).await?),
+      })
+    };
+    let connected = match self.connect_timeout {
+      Some(timeout) => tokio::time::timeout(timeout, connect)
+        .await
+        .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into())),
+      None => connect.await,
+    };
+    match connected {
+      Ok(upstream) => {
+        pool.report_success(idx);
+        Ok((upstream, guard))
+      }
+      Err(e) => {
+        pool.report_failure(idx);
+        Err(io::Error::new(e.kind(), format!("`{}`: {e}", node.url)))
+      }
+    }
+  }
+
+  /// Terminates TLS; returns the decrypted stream and the server name requested by the client.
+  async fn accept_tls(&self, tls: &SniRouting, stream: TcpStream) -> io::Result<(Box<dyn StreamIo>, Option<String>)> {
+    let handshake = async {
+      let start = LazyConfigAcceptor::new(Acceptor::default(), stream).await?;
+      let server_name = start.client_hello().server_name().map(str::to_owned);
+      let stream = start.into_stream(tls.certificate(server_name.as_deref())).await?;
+      Ok::<_, io::Error>((Box::new(stream) as Box<dyn StreamIo>, server_name))
+    };
+    tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, handshake)
+      .await
+      .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into()))
+  }
+
+  async fn handle_tcp(self: Arc<Self>, stream: TcpStream, peer: SocketAddr) {
+    let (client, pool) = match &self.tls {
+      Some(tls) => match self.accept_tls(tls, stream).await {
+        Ok((client, server_name)) => (client, tls.route(server_name.as_deref()).unwrap_or(&self.pool)),
+        Err(e) => {
+          tracing::debug!(%peer, error = ?e, "TLS handshake failed");
+          return;
+        }
+      },
+      None => (Box::new(stream) as Box<dyn StreamIo>, &self.pool),
+    };
+    let (upstream, _guard) = match self.connect(pool, peer).await {
+      Ok(connected) => connected,
+      Err(e) => {
+        tracing::warn!(%peer, error = ?e, "Can't connect to upstream");
+        return;
+      }
+    };
+    match relay(client, upstream, self.idle_timeout).await {
+      Ok((sent, received)) => tracing::debug!(%peer, sent, received, "Connection is closed"),
+      Err(e) => tracing::debug!(%peer, error = ?e, "Connection is closed"),
+    }
+  }
+
+  /// Elects an upstream for the new client and starts forwarding its replies.
+  async fn open_udp_session(
+    &self,
+    peer: SocketAddr,
+    upstreams: &[Option<SocketAddr>],
+    listener: &Arc<UdpSocket>,
+    sessions: &UdpSessions,
+    tasks: &mut JoinSet<()>,
+  ) -> io::Result<Arc<UdpSession>> {
+    let idx = self
+      .pool
+      .select_for_peer(peer.ip())
+      .ok_or_else(|| io::Error::new(io::ErrorKind::NotConnected, "every upstream is unavailable"))?;
+    self.pool.breaker_acquire(idx);
+    let upstream = connect_udp(upstreams[idx]).await.inspect_err(|_| {
+      self.pool.report_failure(idx);
+    })?;
+    let session = Arc::new(UdpSession {
+      upstream,
+      last_active: Mutex::new(Instant::now()),
+    });
+    sessions.lock().unwrap().insert(peer, session.clone());
+
+    let (pool, guard) = (self.pool.clone(), self.pool.track_connection(idx));
+    let (listener, sessions, session_ref) = (listener.clone(), sessions.clone(), session.clone());
+    let idle_timeout = self.idle_timeout.unwrap_or(DEFAULT_UDP_IDLE_TIMEOUT);
+    tasks.spawn(async move {
+      let _guard = guard;
+      let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+      let mut replied = false;
+      loop {
+        tokio::select! {
+          received = session_ref.upstream.recv(&mut buf) => match received {
+            Ok(len) => {
+              replied = true;
+              pool.report_success(idx);
+              *session_ref.last_active.lock().unwrap() = Instant::now();
+              if let Err(e) = listener.send_to(&buf[..len], peer).await {
+                tracing::debug!(%peer, error = ?e, "Can't send datagram to client");
+              }
+            }
+            Err(e) => {
+              pool.report_failure(idx);
+              tracing::debug!(%peer, error = ?e, "Upstream session is closed");
+              break;
+            }
+          },
+          () = idle(&session_ref.last_active, idle_timeout) => {
+            // A session without a single reply is the only failure a silent UDP upstream shows.
+            if !replied {
+              pool.report_failure(idx);
+            }
+            break;
+          }
+        }
+      }
+      sessions.lock().unwrap().remove(&peer);
+    });
+    Ok(session)
+  }
+}
+
+/// Accepts TCP connections of the service and forwards them to its upstreams.
+pub(crate) async fn serve_tcp(service: StreamService) {
+  let proxy = match StreamProxy::new(&service) {
+    Ok(proxy) => Arc::new(proxy),
+    Err(e) => {
+      tracing::error!(service = service.service_name, error = %e, "Can't start stream service");
+      return;
+    }
+  };
+  let listener = match TcpListener::bind(&service.listen).await {
+    Ok(listener) => listener,
+    Err(e) => {
+      tracing::error!(service = service.service_name, listen = service.listen, error = ?e, "Can't bind TCP listener");
+      return;
+    }
+  };
+  tracing::info!(
+    service = service.service_name,
+    listen = service.listen,
+    "TCP stream service is started"
+  );
+
+  loop {
+    match listener.accept().await {
+      Ok((stream, peer)) => {
+        tokio::spawn(proxy.clone().handle_tcp(stream, peer));
+      }
+      Err(e) => tracing::warn!(error = ?e, "Can't accept connection"),
+    }
+  }
+}
+
+/// Receives UDP datagrams of the service and forwards them to its upstreams, keeping a session per client.
+///
+/// Sessions belong to this task, so the port is released as soon as the task is stopped.
+pub(crate) async fn serve_udp(service: StreamService) {
+  let proxy = match StreamProxy::new(&service) {
+    Ok(proxy) => proxy,
+    Err(e) => {
+      tracing::error!(service = service.service_name, error = %e, "Can't start stream service");
+      return;
+    }
+  };
+  let listener = match UdpSocket::bind(&service.listen).await {
+    Ok(listener) => Arc::new(listener),
+    Err(e) => {
+      tracing::error!(service = service.service_name, listen = service.listen, error = ?e, "Can't bind UDP socket");
+      return;
+    }
+  };
+  tracing::info!(
+    service = service.service_name,
+    listen = service.listen,
+    "UDP stream service is started"
+  );
+
+  let upstreams = resolve_udp_upstreams(&proxy.pool).await;
+  let max_sessions = service.max_sessions.unwrap_or(DEFAULT_UDP_MAX_SESSIONS);
+  let sessions = UdpSessions::default();
+  let mut tasks = JoinSet::new();
+  let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+  loop {
+    while tasks.try_join_next().is_some() {}
+
+    let (len, peer) = match listener.recv_from(&mut buf).await {
+      Ok(received) => received,
+      Err(e) => {
+        tracing::debug!(error = ?e, "Can't receive datagram");
+        continue;
+      }
+    };
+    let (session, count) = {
+      let sessions = sessions.lock().unwrap();
+      (sessions.get(&peer).cloned(), sessions.len())
+    };
+    let session = match session {
+      Some(session) => session,
+      None if count >= max_sessions => {
+        tracing::debug!(%peer, max_sessions, "Too many UDP sessions, datagram is dropped");
+        continue;
+      }
+      None => match proxy
+        .open_udp_session(peer, &upstreams, &listener, &sessions, &mut tasks)
+        .await
+      {
+        Ok(session) => session,
+        Err(e) => {
+          tracing::warn!(%peer, error = ?e, "Can't open upstream session");
+          continue;
+        }
+      },
+    };
+    *session.last_active.lock().unwrap() = Instant::now();
+    if let Err(e) = session.upstream.send(&buf[..len]).await {
+      tracing::debug!(%peer, error = ?e, "Can't send datagram to upstream");
+    }
+  }
+}